@@ -0,0 +1,187 @@
+use std::fmt::Debug;
+use std::path::Path;
+
+use anyhow::{Result, anyhow};
+use log::info;
+use serde::Deserialize;
+
+use crate::brightness::Brightness;
+use crate::devices::NumpadDevices;
+use crate::hidraw::{HidId, HidrawBacklight, find_hidraw};
+use crate::touchpad_i2c::TouchpadI2C;
+
+/// Controls the backlight of the numpad.
+pub(crate) trait Backlight: Debug {
+    fn set_brightness(&mut self, brightness: &Brightness) -> Result<()>;
+}
+
+/// How to reach the backlight, as given in the config file.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum BacklightBackend {
+    /// Try hidraw first, then I2C
+    Auto,
+    /// The touchpad's `/dev/hidrawN`, through the kernel's HID driver
+    Hidraw,
+    /// Raw I2C on the touchpad's `/dev/i2c-N`. Needs the `i2c-dev` module.
+    #[default]
+    I2c,
+}
+
+/// The HID feature report that sets the brightness.
+pub(crate) fn brightness_report(brightness: &Brightness) -> [u8; 5] {
+    [0x0d, 0x14, 0x03, brightness.raw(), 0xad]
+}
+
+fn open_hidraw(devices: &NumpadDevices) -> Result<Box<dyn Backlight>> {
+    let path = find_hidraw(Path::new("/sys"), HidId::of(&devices.touchpad))?;
+    Ok(Box::new(HidrawBacklight::open(&path)?))
+}
+
+fn open_i2c(i2c_id: Option<u32>) -> Result<Box<dyn Backlight>> {
+    let i2c_id = i2c_id.ok_or_else(|| {
+        anyhow!("Can't find touchpad I2C ID. Try setting `i2c_device` in the config.")
+    })?;
+    Ok(Box::new(TouchpadI2C::new(i2c_id)?))
+}
+
+type OpenBacklight = Box<dyn FnOnce() -> Result<Box<dyn Backlight>>>;
+
+/// Sends the brightness through hidraw, and switches to I2C for good if that fails.
+///
+/// Some hidraw nodes open fine, but don't take the brightness report.
+struct AutoBacklight {
+    current: Box<dyn Backlight>,
+    /// Opens the I2C backlight, until it has been switched to
+    fallback: Option<OpenBacklight>,
+}
+
+impl Debug for AutoBacklight {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AutoBacklight")
+            .field("current", &self.current)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Backlight for AutoBacklight {
+    fn set_brightness(&mut self, brightness: &Brightness) -> Result<()> {
+        let hidraw_err = match self.current.set_brightness(brightness) {
+            Ok(()) => return Ok(()),
+            Err(err) => err,
+        };
+        let Some(open_fallback) = self.fallback.take() else {
+            return Err(hidraw_err);
+        };
+        info!("{:#}. Falling back to I2C.", hidraw_err);
+        self.current = open_fallback().map_err(|i2c_err| {
+            anyhow!(
+                "Could not set the brightness through hidraw ({:#}) nor I2C ({:#})",
+                hidraw_err,
+                i2c_err
+            )
+        })?;
+        self.current.set_brightness(brightness)
+    }
+}
+
+/// Open the backlight of the touchpad with the given backend.
+pub(crate) fn open_backlight(
+    backend: BacklightBackend,
+    devices: &NumpadDevices,
+) -> Result<Box<dyn Backlight>> {
+    match backend {
+        BacklightBackend::Hidraw => open_hidraw(devices),
+        BacklightBackend::I2c => open_i2c(devices.i2c_id),
+        BacklightBackend::Auto => match open_hidraw(devices) {
+            Ok(hidraw) => {
+                let i2c_id = devices.i2c_id;
+                Ok(Box::new(AutoBacklight {
+                    current: hidraw,
+                    fallback: Some(Box::new(move || open_i2c(i2c_id))),
+                }))
+            }
+            Err(hidraw_err) => {
+                info!("{:#}. Falling back to I2C.", hidraw_err);
+                open_i2c(devices.i2c_id).map_err(|i2c_err| {
+                    anyhow!(
+                        "Could not open the backlight through hidraw ({:#}) nor I2C ({:#})",
+                        hidraw_err,
+                        i2c_err
+                    )
+                })
+            }
+        },
+    }
+}
+
+/// Keeps the brightness it is set to in memory, so that tests can check the sequence.
+///
+/// Clones share the same history, so a clone can be kept to look at it after handing
+/// the backlight over.
+#[cfg(test)]
+#[derive(Debug, Default, Clone)]
+pub(crate) struct RecordingBacklight {
+    history: std::rc::Rc<std::cell::RefCell<Vec<Brightness>>>,
+}
+
+#[cfg(test)]
+impl RecordingBacklight {
+    /// The brightness it was set to so far, oldest first.
+    pub(crate) fn history(&self) -> Vec<Brightness> {
+        self.history.borrow().clone()
+    }
+}
+
+#[cfg(test)]
+impl Backlight for RecordingBacklight {
+    fn set_brightness(&mut self, brightness: &Brightness) -> Result<()> {
+        self.history.borrow_mut().push(brightness.clone());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brightness::default_level;
+
+    /// A hidraw node that doesn't take the report.
+    #[derive(Debug)]
+    struct BrokenBacklight;
+
+    impl Backlight for BrokenBacklight {
+        fn set_brightness(&mut self, _brightness: &Brightness) -> Result<()> {
+            Err(anyhow!("Broken pipe"))
+        }
+    }
+
+    #[test]
+    fn test_auto_fallback() {
+        let i2c = RecordingBacklight::default();
+        let history = i2c.clone();
+        let mut backlight = AutoBacklight {
+            current: Box::new(BrokenBacklight),
+            fallback: Some(Box::new(move || Ok(Box::new(i2c)))),
+        };
+        backlight.set_brightness(&default_level("full")).unwrap();
+        backlight.set_brightness(&Brightness::off()).unwrap();
+        assert_eq!(
+            history.history(),
+            [default_level("full"), Brightness::off()]
+        );
+
+        let mut backlight = AutoBacklight {
+            current: Box::new(BrokenBacklight),
+            fallback: Some(Box::new(|| Err(anyhow!("No I2C")))),
+        };
+        let err = backlight.set_brightness(&Brightness::off()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Could not set the brightness through hidraw (Broken pipe) nor I2C (No I2C)"
+        );
+        // tried only once, so that the reconnect starts afresh
+        let err = backlight.set_brightness(&Brightness::off()).unwrap_err();
+        assert_eq!(err.to_string(), "Broken pipe");
+    }
+}
@@ -0,0 +1,297 @@
+use anyhow::{Result, anyhow, ensure};
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::util::CustomDuration;
+
+/// A named brightness level of the numpad backlight.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Brightness {
+    name: String,
+    /// The byte sent to the firmware. 0 turns the backlight off, 1 is the brightest
+    /// and 31 the dimmest.
+    value: u8,
+}
+
+impl Brightness {
+    const OFF: &str = "off";
+    /// The values that the levels may have, from the brightest to the dimmest
+    const LEVEL_VALUES: std::ops::RangeInclusive<u8> = 1..=31;
+
+    fn new(name: &str, value: u8) -> Self {
+        Self {
+            name: name.to_owned(),
+            value,
+        }
+    }
+
+    /// The backlight turned off, while the numpad is.
+    pub(crate) fn off() -> Self {
+        Self::new(Self::OFF, 0)
+    }
+
+    /// One of the values that the backlight goes through while fading to `target`.
+    pub(crate) fn fading_to(target: &Self, value: u8) -> Self {
+        Self {
+            name: format!("{} (fading)", target.name),
+            value,
+        }
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn raw(&self) -> u8 {
+        self.value
+    }
+}
+
+impl std::fmt::Display for Brightness {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+impl Serialize for Brightness {
+    /// Only the name, since that's what the users know it by.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.name)
+    }
+}
+
+/// Which way holding the calc button goes through the levels.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum CycleDirection {
+    /// Dimmest to brightest, then back to the dimmest
+    #[default]
+    Up,
+    /// Brightest to dimmest, then back to the brightest
+    Down,
+}
+
+/// The brightness levels, as given in the config file.
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct BrightnessConfig {
+    /// From the dimmest to the brightest
+    levels: Vec<Brightness>,
+    /// Name of the level to start with. The brightest if not given.
+    default: Option<String>,
+    cycle: CycleDirection,
+    /// How long the backlight takes to fade to a new level, in ms.
+    /// If not given, it changes at once.
+    fade_ms: Option<u64>,
+}
+
+impl Default for BrightnessConfig {
+    fn default() -> Self {
+        Self {
+            levels: vec![
+                Brightness::new("low", 31),
+                Brightness::new("half", 24),
+                Brightness::new("full", 1),
+            ],
+            default: None,
+            cycle: Default::default(),
+            fade_ms: None,
+        }
+    }
+}
+
+impl BrightnessConfig {
+    pub(crate) fn fade(&self) -> Option<CustomDuration> {
+        self.fade_ms
+            .filter(|&ms| ms > 0)
+            .map(CustomDuration::from_millis)
+    }
+}
+
+/// The validated brightness levels.
+#[derive(Debug)]
+pub(crate) struct BrightnessLevels {
+    levels: Vec<Brightness>,
+    default: usize,
+    cycle: CycleDirection,
+}
+
+impl BrightnessLevels {
+    pub(crate) fn resolve(config: &BrightnessConfig) -> Result<Self> {
+        let levels = config.levels.clone();
+        ensure!(
+            !levels.is_empty(),
+            "There must be at least one brightness level"
+        );
+        for (i, level) in levels.iter().enumerate() {
+            ensure!(
+                !level.name.eq_ignore_ascii_case(Brightness::OFF),
+                "The brightness level {:?} is reserved",
+                Brightness::OFF
+            );
+            ensure!(
+                !levels[..i]
+                    .iter()
+                    .any(|other| other.name.eq_ignore_ascii_case(&level.name)),
+                "Duplicate brightness level {:?}",
+                level.name
+            );
+            ensure!(
+                Brightness::LEVEL_VALUES.contains(&level.value),
+                "The value of the brightness level {:?} must be between 1 and 31, not {}",
+                level.name,
+                level.value
+            );
+            if let Some(prev) = i.checked_sub(1).map(|j| &levels[j]) {
+                ensure!(
+                    level.value < prev.value,
+                    "The brightness levels must go from the dimmest to the brightest, \
+                    but {:?} isn't brighter than {:?}",
+                    level.name,
+                    prev.name
+                );
+            }
+        }
+        let default = match &config.default {
+            Some(name) => Self::position(&levels, name)
+                .ok_or_else(|| anyhow!("Unknown default brightness level {:?}", name))?,
+            None => levels.len() - 1,
+        };
+        Ok(Self {
+            levels,
+            default,
+            cycle: config.cycle,
+        })
+    }
+
+    fn position(levels: &[Brightness], name: &str) -> Option<usize> {
+        levels
+            .iter()
+            .position(|level| level.name.eq_ignore_ascii_case(name))
+    }
+
+    pub(crate) fn default_level(&self) -> Brightness {
+        self.levels[self.default].clone()
+    }
+
+    /// Used while the numpad is idle.
+    pub(crate) fn dimmest(&self) -> Brightness {
+        self.levels[0].clone()
+    }
+
+    /// Find a level by its name, ignoring the case. `off` is always there.
+    pub(crate) fn get(&self, name: &str) -> Result<Brightness> {
+        if name.eq_ignore_ascii_case(Brightness::OFF) {
+            return Ok(Brightness::off());
+        }
+        Self::position(&self.levels, name)
+            .map(|i| self.levels[i].clone())
+            .ok_or_else(|| {
+                let names: Vec<_> = self.levels.iter().map(Brightness::name).collect();
+                anyhow!(
+                    "Unknown brightness level {:?}. Expected one of off, {}",
+                    name,
+                    names.join(", ")
+                )
+            })
+    }
+
+    /// The level after `cur`, when cycling. Starts from the default if `cur` isn't a level.
+    pub(crate) fn next(&self, cur: &Brightness) -> Brightness {
+        let len = self.levels.len();
+        let next = match Self::position(&self.levels, &cur.name) {
+            None => self.default,
+            Some(i) => match self.cycle {
+                CycleDirection::Up => (i + 1) % len,
+                CycleDirection::Down => (i + len - 1) % len,
+            },
+        };
+        self.levels[next].clone()
+    }
+}
+
+/// Get one of the default levels, by name.
+#[cfg(test)]
+pub(crate) fn default_level(name: &str) -> Brightness {
+    BrightnessLevels::resolve(&Default::default())
+        .unwrap()
+        .get(name)
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn levels(config: &str) -> Result<BrightnessLevels> {
+        BrightnessLevels::resolve(&toml::from_str(config).unwrap())
+    }
+
+    fn cycle(levels: &BrightnessLevels, start: Brightness, times: usize) -> Vec<String> {
+        std::iter::successors(Some(start), |cur| Some(levels.next(cur)))
+            .skip(1)
+            .take(times)
+            .map(|level| level.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_default_levels() {
+        let levels = levels("").unwrap();
+        assert_eq!(levels.default_level(), Brightness::new("full", 1));
+        assert_eq!(levels.dimmest(), Brightness::new("low", 31));
+        assert_eq!(levels.get("Half").unwrap(), Brightness::new("half", 24));
+        assert_eq!(levels.get("off").unwrap().raw(), 0);
+        assert!(levels.get("blinding").is_err());
+        assert_eq!(
+            cycle(&levels, Brightness::off(), 4),
+            ["full", "low", "half", "full"]
+        );
+    }
+
+    #[test]
+    fn test_custom_levels() {
+        let custom = levels(
+            r#"
+            levels = [
+                { name = "night", value = 31 },
+                { name = "dim", value = 20 },
+                { name = "normal", value = 10 },
+                { name = "max", value = 1 },
+            ]
+            default = "normal"
+            cycle = "down"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(custom.default_level().name(), "normal");
+        assert_eq!(
+            cycle(&custom, custom.default_level(), 4),
+            ["dim", "night", "max", "normal"]
+        );
+
+        assert!(levels("levels = []").is_err());
+        assert!(levels(r#"default = "max""#).is_err());
+        let reserved = r#"levels = [{ name = "off", value = 5 }]"#;
+        assert!(levels(reserved).is_err());
+        let duplicate = r#"levels = [{ name = "a", value = 6 }, { name = "A", value = 5 }]"#;
+        assert!(levels(duplicate).is_err());
+        for value in [0, 32] {
+            let out_of_range = format!(r#"levels = [{{ name = "a", value = {} }}]"#, value);
+            assert!(levels(&out_of_range).is_err());
+        }
+        let brightest_first = r#"levels = [{ name = "a", value = 1 }, { name = "b", value = 31 }]"#;
+        let err = levels(brightest_first).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The brightness levels must go from the dimmest to the brightest, \
+            but \"b\" isn't brighter than \"a\""
+        );
+    }
+
+    #[test]
+    fn test_serialize() {
+        let json = serde_json::to_string(&default_level("half")).unwrap();
+        assert_eq!(json, r#""half""#);
+    }
+}
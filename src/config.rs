@@ -1,7 +1,16 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Result, bail};
 use evdev_rs::enums::EV_KEY;
 use serde::Deserialize;
 
-use crate::numpad_layout::SupportedLayout;
+use crate::backlight::BacklightBackend;
+use crate::brightness::BrightnessConfig;
+use crate::dummy_keyboard::AutorepeatConfig;
+use crate::gestures::GestureConfig;
+use crate::numpad_layout::LayoutConfig;
+use crate::palm::PalmConfig;
+use crate::util::CustomDuration;
 
 #[derive(Debug, PartialEq, Eq, Hash, Deserialize)]
 #[serde(rename_all = "snake_case", untagged)]
@@ -19,13 +28,50 @@ impl Default for CustomCommand {
     }
 }
 
+/// Pins a device, instead of autodetecting it.
 #[derive(Debug, PartialEq, Eq, Hash, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum DeviceSelector {
+    /// Path to the device node, or a symlink to it (eg. in `/dev/input/by-id/`)
+    Path(PathBuf),
+    /// Name of the device
+    Name { name: String },
+}
+
+/// What to do once the numpad has been idle for `idle_timeout` seconds.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum IdleAction {
+    /// Turn off numlock, like holding the numlock button does
+    #[default]
+    NumlockOff,
+    /// Keep numlock on, but dim the backlight until the numpad is used again
+    Dim,
+}
+
+/// The D-Bus bus to expose the numpad on.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum DbusBus {
+    /// Needs the policy file in `tools/`, to be allowed to own the name
+    #[default]
+    System,
+    Session,
+    Off,
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
 pub(crate) struct Config {
-    layout: SupportedLayout,
+    /// If not given, the layout is autodetected.
+    layout: Option<LayoutConfig>,
 
     #[serde(default = "default_numlock")]
     disable_numlock_on_start: bool,
 
+    /// Turn numlock back on at startup if it was on when the daemon stopped.
+    #[serde(default)]
+    restore_numlock: bool,
+
     #[serde(default)]
     calc_start_command: CustomCommand,
 
@@ -33,16 +79,79 @@ pub(crate) struct Config {
 
     #[serde(default)]
     disable_numpad: bool,
+
+    touchpad_device: Option<DeviceSelector>,
+
+    keyboard_device: Option<DeviceSelector>,
+
+    i2c_device: Option<DeviceSelector>,
+
+    #[serde(default)]
+    backlight: BacklightBackend,
+
+    #[serde(default)]
+    brightness: BrightnessConfig,
+
+    /// In seconds. If not given, the numpad never times out.
+    idle_timeout: Option<u64>,
+
+    #[serde(default)]
+    idle_action: IdleAction,
+
+    #[serde(default)]
+    gestures: GestureConfig,
+
+    #[serde(default)]
+    palm_rejection: PalmConfig,
+
+    /// In ms. If not given, the numpad works while typing.
+    disable_while_typing_ms: Option<u64>,
+
+    #[serde(default)]
+    autorepeat: AutorepeatConfig,
+
+    /// An empty path disables the control socket.
+    #[serde(default = "default_control_socket")]
+    control_socket: PathBuf,
+
+    /// The group whose members may use the control socket, besides the daemon's user.
+    #[serde(default)]
+    control_group: Option<String>,
+
+    /// Only used if built with the `dbus` feature.
+    #[serde(default)]
+    dbus: DbusBus,
+
+    /// Reload the config whenever the file changes.
+    #[serde(default)]
+    watch_config: bool,
+
+    /// Where the brightness and numlock are kept across restarts. An empty path disables it.
+    #[serde(default = "default_state_dir")]
+    state_dir: PathBuf,
 }
 
 fn default_numlock() -> bool {
     true
 }
 
+fn default_control_socket() -> PathBuf {
+    PathBuf::from("/run/asus-numpad/control.sock")
+}
+
+fn default_state_dir() -> PathBuf {
+    PathBuf::from("/var/lib/asus-numpad")
+}
+
 impl Config {
     /// Get a reference to the config's layout.
-    pub(crate) fn layout(&self) -> &SupportedLayout {
-        &self.layout
+    pub(crate) fn layout(&self) -> Option<&LayoutConfig> {
+        self.layout.as_ref()
+    }
+
+    /// Use the given layout, instead of the one in the file.
+    pub(crate) fn set_layout(&mut self, layout: LayoutConfig) {
+        self.layout = Some(layout);
     }
 
     /// Get a reference to the config's disable numlock on start.
@@ -50,6 +159,10 @@ impl Config {
         self.disable_numlock_on_start
     }
 
+    pub(crate) fn restore_numlock(&self) -> bool {
+        self.restore_numlock
+    }
+
     /// Get a reference to the config's calc start command.
     pub(crate) fn calc_start_command(&self) -> &CustomCommand {
         &self.calc_start_command
@@ -63,4 +176,94 @@ impl Config {
     pub(crate) fn disable_numpad(&self) -> bool {
         self.disable_numpad
     }
+
+    /// Get a reference to the config's pinned touchpad evdev.
+    pub(crate) fn touchpad_device(&self) -> Option<&DeviceSelector> {
+        self.touchpad_device.as_ref()
+    }
+
+    /// Get a reference to the config's pinned keyboard evdev.
+    pub(crate) fn keyboard_device(&self) -> Option<&DeviceSelector> {
+        self.keyboard_device.as_ref()
+    }
+
+    /// Get a reference to the config's pinned touchpad I2C bus.
+    pub(crate) fn i2c_device(&self) -> Option<&DeviceSelector> {
+        self.i2c_device.as_ref()
+    }
+
+    pub(crate) fn backlight(&self) -> BacklightBackend {
+        self.backlight
+    }
+
+    pub(crate) fn brightness(&self) -> &BrightnessConfig {
+        &self.brightness
+    }
+
+    /// The longest idle timeout, in seconds. Longer ones are as good as none.
+    const MAX_IDLE_TIMEOUT: u64 = 24 * 60 * 60;
+
+    /// Get the config's idle timeout, checking that it is in range.
+    pub(crate) fn idle_timeout(&self) -> Result<Option<CustomDuration>> {
+        let Some(secs) = self.idle_timeout else {
+            return Ok(None);
+        };
+        if !(1..=Self::MAX_IDLE_TIMEOUT).contains(&secs) {
+            bail!(
+                "idle_timeout must be between 1 and {} seconds, not {}",
+                Self::MAX_IDLE_TIMEOUT,
+                secs
+            );
+        }
+        Ok(Some(CustomDuration::from_secs(secs)))
+    }
+
+    /// Get the config's idle action.
+    pub(crate) fn idle_action(&self) -> IdleAction {
+        self.idle_action
+    }
+
+    /// Get a reference to the config's gesture thresholds.
+    pub(crate) fn gestures(&self) -> &GestureConfig {
+        &self.gestures
+    }
+
+    /// Get a reference to the config's palm rejection settings.
+    pub(crate) fn palm_rejection(&self) -> &PalmConfig {
+        &self.palm_rejection
+    }
+
+    /// Get the config's disable while typing window.
+    pub(crate) fn disable_while_typing(&self) -> Option<CustomDuration> {
+        self.disable_while_typing_ms
+            .map(CustomDuration::from_millis)
+    }
+
+    /// Get a reference to the config's autorepeat settings.
+    pub(crate) fn autorepeat(&self) -> &AutorepeatConfig {
+        &self.autorepeat
+    }
+
+    /// Get the config's control socket path, if it is enabled.
+    pub(crate) fn control_socket(&self) -> Option<&Path> {
+        Some(self.control_socket.as_path()).filter(|path| !path.as_os_str().is_empty())
+    }
+
+    pub(crate) fn control_group(&self) -> Option<&str> {
+        self.control_group.as_deref()
+    }
+
+    #[cfg_attr(not(feature = "dbus"), allow(dead_code))]
+    pub(crate) fn dbus(&self) -> DbusBus {
+        self.dbus
+    }
+
+    pub(crate) fn watch_config(&self) -> bool {
+        self.watch_config
+    }
+
+    /// Get the config's state directory, if saving the state is enabled.
+    pub(crate) fn state_dir(&self) -> Option<&Path> {
+        Some(self.state_dir.as_path()).filter(|path| !path.as_os_str().is_empty())
+    }
 }
@@ -0,0 +1,467 @@
+use evdev_rs::enums::{EV_ABS, EventCode};
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::Point;
+use crate::palm::{ContactSize, PalmThresholds};
+
+/// Value of `ABS_MT_TOOL_TYPE` for contacts that the touchpad considers palms.
+///
+/// There is no `BTN_TOOL_PALM` in the evdev protocol, this is the only way it reports them.
+const MT_TOOL_PALM: i32 = 2;
+
+/// What to do when more fingers touch the pad while a key is being pressed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ExtraFingers {
+    /// The user is moving the pointer or scrolling, cancel the key
+    #[default]
+    Pointer,
+    /// Keep pressing the key, as long as the first finger stays down
+    Ignore,
+}
+
+/// The last values of a multitouch slot.
+///
+/// The kernel only reports the values that change, even when a new contact starts in the
+/// slot, so they are kept after the contact lifts.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct SlotValues {
+    pub(crate) x: i32,
+    pub(crate) y: i32,
+    pub(crate) touch_major: i32,
+    pub(crate) width_major: i32,
+    pub(crate) pressure: i32,
+    pub(crate) tool_type: i32,
+}
+
+impl SlotValues {
+    fn pos(&self) -> Point {
+        Point {
+            x: self.x,
+            y: self.y,
+        }
+    }
+
+    fn size(&self) -> ContactSize {
+        ContactSize {
+            touch_major: self.touch_major,
+            width_major: self.width_major,
+            pressure: self.pressure,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Contact {
+    tracking_id: i32,
+    /// Once a contact is considered a palm, it stays one until it lifts
+    palm: bool,
+}
+
+impl Contact {
+    fn is_finger(&self) -> bool {
+        !self.palm
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Slot {
+    values: SlotValues,
+    /// `None` while nothing touches the slot
+    contact: Option<Contact>,
+}
+
+/// How the contact that owns the current key changed during an evdev frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Frame {
+    /// No contact owns the key
+    Idle,
+    /// The first finger touched down at the given position
+    Touch(Point),
+    /// The owning finger is still down, at the given position
+    Hold(Point),
+    /// The owning finger lifted
+    Lift,
+    /// More fingers touched down while the owning finger was down,
+    /// or the owning finger turned out to be a palm
+    Cancel,
+}
+
+/// Tracks every contact on the touchpad, using the multitouch slot protocol.
+#[derive(Debug)]
+pub(crate) struct Contacts {
+    extra_fingers: ExtraFingers,
+    palm: PalmThresholds,
+    slots: Vec<Slot>,
+    cur_slot: usize,
+    /// Slot of the contact that owns the current key
+    owner: Option<usize>,
+    /// The current owner has already been cancelled
+    cancelled: bool,
+    /// Don't pick a new owner until all fingers lift
+    blocked: bool,
+}
+
+impl Contacts {
+    /// `cur_slot` and `slots` are the device's current `ABS_MT_SLOT` and slot values,
+    /// since the kernel only reports changes.
+    pub(crate) fn new(
+        extra_fingers: ExtraFingers,
+        palm: PalmThresholds,
+        cur_slot: i32,
+        slots: &[SlotValues],
+    ) -> Self {
+        let slots = slots
+            .iter()
+            .map(|&values| Slot {
+                values,
+                contact: None,
+            })
+            .collect();
+        Self {
+            extra_fingers,
+            palm,
+            slots,
+            cur_slot: cur_slot.max(0) as usize,
+            owner: None,
+            cancelled: false,
+            blocked: false,
+        }
+    }
+
+    /// Use new settings, and forget the current contacts. Their slot values are kept.
+    pub(crate) fn restart(&mut self, extra_fingers: ExtraFingers, palm: PalmThresholds) {
+        for slot in &mut self.slots {
+            slot.contact = None;
+        }
+        self.extra_fingers = extra_fingers;
+        self.palm = palm;
+        self.owner = None;
+        self.cancelled = false;
+        self.blocked = false;
+    }
+
+    fn cur_slot(&mut self) -> &mut Slot {
+        if self.cur_slot >= self.slots.len() {
+            self.slots.resize(self.cur_slot + 1, Slot::default());
+        }
+        &mut self.slots[self.cur_slot]
+    }
+
+    /// Update the contacts from a multitouch event. Other events are ignored.
+    pub(crate) fn handle_event(&mut self, event_code: &EventCode, value: i32) {
+        if let EventCode::EV_ABS(EV_ABS::ABS_MT_SLOT) = event_code {
+            self.cur_slot = value.max(0) as usize;
+            return;
+        }
+        let EventCode::EV_ABS(axis) = event_code else {
+            return;
+        };
+        let slot = self.cur_slot();
+        let values = &mut slot.values;
+        match axis {
+            EV_ABS::ABS_MT_TRACKING_ID => {
+                slot.contact = (value >= 0).then_some(Contact {
+                    tracking_id: value,
+                    palm: false,
+                });
+            }
+            EV_ABS::ABS_MT_POSITION_X => values.x = value,
+            EV_ABS::ABS_MT_POSITION_Y => values.y = value,
+            EV_ABS::ABS_MT_TOUCH_MAJOR => values.touch_major = value,
+            EV_ABS::ABS_MT_WIDTH_MAJOR => values.width_major = value,
+            EV_ABS::ABS_MT_PRESSURE => values.pressure = value,
+            EV_ABS::ABS_MT_TOOL_TYPE => values.tool_type = value,
+            _ => (),
+        }
+    }
+
+    /// Whether anything, fingers or palms, is touching the pad.
+    pub(crate) fn any_down(&self) -> bool {
+        self.slots.iter().any(|slot| slot.contact.is_some())
+    }
+
+    /// Called at the end of an evdev frame (`SYN_REPORT`), once all its events are handled.
+    pub(crate) fn end_frame(&mut self) -> Frame {
+        for slot in &mut self.slots {
+            let Some(contact) = &mut slot.contact else {
+                continue;
+            };
+            if !contact.palm
+                && ((self.palm.use_tool_type && slot.values.tool_type == MT_TOOL_PALM)
+                    || self.palm.exceeded_by(&slot.values.size()))
+            {
+                debug!("Palm detected: {:?}", slot.values);
+                contact.palm = true;
+            }
+        }
+        // palms are ignored, as if they weren't touching the pad
+        let active = self
+            .slots
+            .iter()
+            .filter_map(|slot| slot.contact)
+            .filter(Contact::is_finger)
+            .count();
+        if active == 0 {
+            self.blocked = false;
+        }
+        if let Some(owner) = self.owner {
+            let slot = self.slots[owner];
+            return match slot.contact {
+                None => {
+                    self.owner = None;
+                    // the remaining fingers were resting, they shouldn't start a new tap
+                    self.blocked = active > 0;
+                    Frame::Lift
+                }
+                Some(contact)
+                    if !self.cancelled
+                        && (contact.palm
+                            || (active > 1 && self.extra_fingers == ExtraFingers::Pointer)) =>
+                {
+                    self.cancelled = true;
+                    Frame::Cancel
+                }
+                Some(_) => Frame::Hold(slot.values.pos()),
+            };
+        }
+        if active == 0 || self.blocked {
+            return Frame::Idle;
+        }
+        if active > 1 && self.extra_fingers == ExtraFingers::Pointer {
+            // multiple fingers landing together
+            self.blocked = true;
+            return Frame::Idle;
+        }
+        // the oldest contact wins
+        let Some((owner, slot)) = self
+            .slots
+            .iter()
+            .enumerate()
+            .filter(|(_, slot)| slot.contact.is_some_and(|contact| contact.is_finger()))
+            .min_by_key(|(_, slot)| slot.contact.map(|contact| contact.tracking_id))
+        else {
+            return Frame::Idle;
+        };
+        self.owner = Some(owner);
+        self.cancelled = false;
+        Frame::Touch(slot.values.pos())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::palm::PalmAxes;
+
+    const SLOT: EventCode = EventCode::EV_ABS(EV_ABS::ABS_MT_SLOT);
+    const TRACKING_ID: EventCode = EventCode::EV_ABS(EV_ABS::ABS_MT_TRACKING_ID);
+    const X: EventCode = EventCode::EV_ABS(EV_ABS::ABS_MT_POSITION_X);
+    const Y: EventCode = EventCode::EV_ABS(EV_ABS::ABS_MT_POSITION_Y);
+    const TOUCH_MAJOR: EventCode = EventCode::EV_ABS(EV_ABS::ABS_MT_TOUCH_MAJOR);
+    const TOOL_TYPE: EventCode = EventCode::EV_ABS(EV_ABS::ABS_MT_TOOL_TYPE);
+
+    fn frame(contacts: &mut Contacts, events: &[(EventCode, i32)]) -> Frame {
+        for (code, value) in events {
+            contacts.handle_event(code, *value);
+        }
+        contacts.end_frame()
+    }
+
+    fn point(x: i32, y: i32) -> Point {
+        Point { x, y }
+    }
+
+    fn fingers_only() -> PalmThresholds {
+        PalmThresholds::resolve(&Default::default(), &Default::default())
+    }
+
+    #[test]
+    fn test_single_finger() {
+        let mut contacts = Contacts::new(ExtraFingers::Pointer, fingers_only(), 0, &[]);
+        assert_eq!(frame(&mut contacts, &[]), Frame::Idle);
+        assert_eq!(
+            frame(&mut contacts, &[(TRACKING_ID, 10), (X, 100), (Y, 200)]),
+            Frame::Touch(point(100, 200))
+        );
+        assert_eq!(
+            frame(&mut contacts, &[(X, 110)]),
+            Frame::Hold(point(110, 200))
+        );
+        assert_eq!(frame(&mut contacts, &[(TRACKING_ID, -1)]), Frame::Lift);
+        assert_eq!(frame(&mut contacts, &[]), Frame::Idle);
+    }
+
+    #[test]
+    fn test_second_finger_is_pointer() {
+        let mut contacts = Contacts::new(ExtraFingers::Pointer, fingers_only(), 0, &[]);
+        frame(&mut contacts, &[(TRACKING_ID, 10), (X, 100), (Y, 200)]);
+        // the second finger must not move the position of the first
+        assert_eq!(
+            frame(
+                &mut contacts,
+                &[(SLOT, 1), (TRACKING_ID, 11), (X, 900), (Y, 900)]
+            ),
+            Frame::Cancel
+        );
+        assert_eq!(
+            frame(&mut contacts, &[(X, 950)]),
+            Frame::Hold(point(100, 200))
+        );
+        assert_eq!(
+            frame(&mut contacts, &[(SLOT, 0), (TRACKING_ID, -1)]),
+            Frame::Lift
+        );
+        // the remaining finger doesn't start a new tap
+        assert_eq!(frame(&mut contacts, &[(SLOT, 1), (X, 960)]), Frame::Idle);
+        assert_eq!(frame(&mut contacts, &[(TRACKING_ID, -1)]), Frame::Idle);
+        assert_eq!(
+            frame(&mut contacts, &[(TRACKING_ID, 12), (X, 300), (Y, 300)]),
+            Frame::Touch(point(300, 300))
+        );
+    }
+
+    #[test]
+    fn test_fingers_landing_together() {
+        let mut contacts = Contacts::new(ExtraFingers::Pointer, fingers_only(), 0, &[]);
+        assert_eq!(
+            frame(
+                &mut contacts,
+                &[(TRACKING_ID, 10), (SLOT, 1), (TRACKING_ID, 11)]
+            ),
+            Frame::Idle
+        );
+        assert_eq!(
+            frame(&mut contacts, &[(SLOT, 0), (TRACKING_ID, -1)]),
+            Frame::Idle
+        );
+    }
+
+    #[test]
+    fn test_ignore_extra_fingers() {
+        let mut contacts = Contacts::new(ExtraFingers::Ignore, fingers_only(), 2, &[]);
+        frame(&mut contacts, &[(TRACKING_ID, 10), (X, 100), (Y, 200)]);
+        assert_eq!(
+            frame(
+                &mut contacts,
+                &[(SLOT, 0), (TRACKING_ID, 11), (X, 900), (Y, 900)]
+            ),
+            Frame::Hold(point(100, 200))
+        );
+        assert_eq!(
+            frame(&mut contacts, &[(SLOT, 2), (TRACKING_ID, -1)]),
+            Frame::Lift
+        );
+    }
+
+    #[test]
+    fn test_palm() {
+        let config = toml::from_str("touch_major = { units = 50 }").unwrap();
+        let axes = PalmAxes {
+            touch_major: Some(255),
+            ..Default::default()
+        };
+        let palm = PalmThresholds::resolve(&config, &axes);
+        let mut contacts = Contacts::new(ExtraFingers::Pointer, palm, 0, &[]);
+
+        // a resting palm doesn't stop the finger from typing
+        assert_eq!(
+            frame(&mut contacts, &[(TRACKING_ID, 10), (TOUCH_MAJOR, 80)]),
+            Frame::Idle
+        );
+        assert_eq!(
+            frame(
+                &mut contacts,
+                &[
+                    (SLOT, 1),
+                    (TRACKING_ID, 11),
+                    (X, 100),
+                    (Y, 200),
+                    (TOUCH_MAJOR, 20)
+                ]
+            ),
+            Frame::Touch(point(100, 200))
+        );
+        assert_eq!(frame(&mut contacts, &[(TRACKING_ID, -1)]), Frame::Lift);
+
+        // a palm that starts small is cancelled once it grows
+        assert_eq!(
+            frame(
+                &mut contacts,
+                &[(TRACKING_ID, 12), (X, 300), (Y, 300), (TOUCH_MAJOR, 20)]
+            ),
+            Frame::Touch(point(300, 300))
+        );
+        assert_eq!(frame(&mut contacts, &[(TOUCH_MAJOR, 60)]), Frame::Cancel);
+        // and stays a palm even if it shrinks
+        assert_eq!(
+            frame(&mut contacts, &[(TOUCH_MAJOR, 20)]),
+            Frame::Hold(point(300, 300))
+        );
+        assert_eq!(frame(&mut contacts, &[(TRACKING_ID, -1)]), Frame::Lift);
+    }
+
+    #[test]
+    fn test_unchanged_values() {
+        let mut contacts = Contacts::new(ExtraFingers::Pointer, fingers_only(), 0, &[]);
+        frame(&mut contacts, &[(TRACKING_ID, 10), (X, 100), (Y, 200)]);
+        assert_eq!(frame(&mut contacts, &[(TRACKING_ID, -1)]), Frame::Lift);
+        // the kernel doesn't send X again, since it is the same as the last touch
+        assert_eq!(
+            frame(&mut contacts, &[(TRACKING_ID, 11), (Y, 500)]),
+            Frame::Touch(point(100, 500))
+        );
+        assert_eq!(frame(&mut contacts, &[(TRACKING_ID, -1)]), Frame::Lift);
+
+        // the values from before the daemon started
+        let slots = [SlotValues {
+            x: 300,
+            y: 400,
+            ..Default::default()
+        }];
+        let mut contacts = Contacts::new(ExtraFingers::Pointer, fingers_only(), 0, &slots);
+        assert_eq!(
+            frame(&mut contacts, &[(TRACKING_ID, 10), (X, 350)]),
+            Frame::Touch(point(350, 400))
+        );
+    }
+
+    #[test]
+    fn test_palm_tool_type() {
+        let mut contacts = Contacts::new(ExtraFingers::Pointer, fingers_only(), 0, &[]);
+        assert_eq!(
+            frame(
+                &mut contacts,
+                &[(TRACKING_ID, 10), (TOOL_TYPE, MT_TOOL_PALM), (X, 100)]
+            ),
+            Frame::Idle
+        );
+    }
+
+    #[test]
+    fn test_palm_after_palm() {
+        let mut contacts = Contacts::new(ExtraFingers::Pointer, fingers_only(), 0, &[]);
+        let palm = [
+            (TRACKING_ID, 10),
+            (TOOL_TYPE, MT_TOOL_PALM),
+            (X, 100),
+            (Y, 200),
+        ];
+        assert_eq!(frame(&mut contacts, &palm), Frame::Idle);
+        assert_eq!(frame(&mut contacts, &[(TRACKING_ID, -1)]), Frame::Idle);
+        // the tool type is the same, so the kernel doesn't send it again
+        assert_eq!(
+            frame(&mut contacts, &[(TRACKING_ID, 11), (X, 120)]),
+            Frame::Idle
+        );
+        assert_eq!(frame(&mut contacts, &[(TRACKING_ID, -1)]), Frame::Idle);
+        assert_eq!(
+            frame(
+                &mut contacts,
+                &[(TRACKING_ID, 12), (TOOL_TYPE, 0), (X, 140)]
+            ),
+            Frame::Touch(point(140, 200))
+        );
+    }
+}
@@ -0,0 +1,400 @@
+use std::ffi::CString;
+use std::fmt::Display;
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{Context, Error, Result, anyhow, bail};
+use clap::Subcommand;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::engine::Status;
+
+/// A command sent to the daemon over the control socket.
+///
+/// On the wire, each request is a single line, like `brightness half`.
+#[derive(Debug, Clone, PartialEq, Eq, Subcommand, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Request {
+    /// Turn on the numpad
+    Enable,
+    /// Turn off the numpad
+    Disable,
+    /// Turn the numpad on or off
+    Toggle,
+    /// Set the brightness of the numpad backlight
+    Brightness {
+        /// The name of one of the configured levels, or off
+        level: String,
+    },
+    /// Switch to the next brightness level, like holding the calc button does
+    CycleBrightness,
+    /// Run the calc command, or stop it if it is running, like dragging the calc button does
+    ToggleCalc,
+    /// Print the numlock, brightness and calc state, as JSON
+    State,
+    /// Reload the config file
+    Reload,
+}
+
+impl Display for Request {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Enable => f.write_str("enable"),
+            Self::Disable => f.write_str("disable"),
+            Self::Toggle => f.write_str("toggle"),
+            Self::Brightness { level } => write!(f, "brightness {}", level),
+            Self::CycleBrightness => f.write_str("cycle-brightness"),
+            Self::ToggleCalc => f.write_str("toggle-calc"),
+            Self::State => f.write_str("state"),
+            Self::Reload => f.write_str("reload"),
+        }
+    }
+}
+
+impl FromStr for Request {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut words = s.split_whitespace();
+        let request = match words.next() {
+            Some("enable") => Self::Enable,
+            Some("disable") => Self::Disable,
+            Some("toggle") => Self::Toggle,
+            Some("brightness") => Self::Brightness {
+                level: words.next().context("Missing brightness level")?.to_owned(),
+            },
+            Some("cycle-brightness") => Self::CycleBrightness,
+            Some("toggle-calc") => Self::ToggleCalc,
+            Some("state") => Self::State,
+            Some("reload") => Self::Reload,
+            _ => bail!("Unknown request {:?}", s),
+        };
+        if words.next().is_some() {
+            bail!("Too many arguments in {:?}", s);
+        }
+        Ok(request)
+    }
+}
+
+/// The daemon's answer to a [`Request`].
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Response {
+    Ok,
+    State(Status),
+    Error(String),
+}
+
+impl Display for Response {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Ok => f.write_str("ok"),
+            Self::State(status) => {
+                let json = serde_json::to_string(status).map_err(|_| std::fmt::Error)?;
+                f.write_str(&json)
+            }
+            Self::Error(msg) => write!(f, "error: {}", msg),
+        }
+    }
+}
+
+/// A connection on the control socket, waiting for its answer.
+///
+/// It is non-blocking, so that a slow client doesn't hold up the touchpad. Its request is
+/// read bit by bit, as it arrives.
+#[derive(Debug)]
+pub(crate) struct Client {
+    stream: UnixStream,
+    /// What has been read of the request so far
+    buf: Vec<u8>,
+}
+
+impl Client {
+    const MAX_REQUEST_LEN: usize = 256;
+
+    /// Read what has arrived, and return the request once its line is complete.
+    fn read_request(&mut self) -> Option<Result<Request>> {
+        let mut chunk = [0; Self::MAX_REQUEST_LEN];
+        loop {
+            let end = match self.stream.read(&mut chunk) {
+                // the client may not end its line before closing its side
+                Ok(0) => self.buf.len(),
+                Ok(n) => {
+                    self.buf.extend_from_slice(&chunk[..n]);
+                    match self.buf.iter().position(|&b| b == b'\n') {
+                        Some(end) => end,
+                        None if self.buf.len() > Self::MAX_REQUEST_LEN => {
+                            return Some(Err(anyhow!("Request is too long")));
+                        }
+                        None => continue,
+                    }
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return None,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Some(Err(err).context("Could not read request")),
+            };
+            let line = String::from_utf8_lossy(&self.buf[..end]);
+            return Some(line.trim().parse());
+        }
+    }
+
+    pub(crate) fn respond(mut self, response: &Response) {
+        if let Err(err) = writeln!(self.stream, "{}", response) {
+            debug!("Could not respond to control client: {}", err);
+        }
+    }
+}
+
+impl AsRawFd for Client {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+/// Look up the ID of the group called `name`.
+fn group_id(name: &str) -> Result<libc::gid_t> {
+    let c_name = CString::new(name)?;
+    let mut buf: Vec<libc::c_char> = vec![0; 1024];
+    loop {
+        let mut group: libc::group = unsafe { std::mem::zeroed() };
+        let mut result = std::ptr::null_mut();
+        let ret = unsafe {
+            libc::getgrnam_r(
+                c_name.as_ptr(),
+                &mut group,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            )
+        };
+        match ret {
+            // the group has too many members for the buffer
+            libc::ERANGE => buf.resize(buf.len() * 2, 0),
+            0 if result.is_null() => bail!("There is no group {:?}", name),
+            0 => return Ok(group.gr_gid),
+            _ => {
+                return Err(std::io::Error::from_raw_os_error(ret))
+                    .with_context(|| format!("Could not look up group {:?}", name));
+            }
+        }
+    }
+}
+
+/// A Unix socket that accepts [`Request`]s, one per connection.
+#[derive(Debug)]
+pub(crate) struct ControlSocket {
+    listener: UnixListener,
+    path: PathBuf,
+    /// The connections whose request hasn't fully arrived yet
+    clients: Vec<Client>,
+}
+
+impl ControlSocket {
+    /// How many connections may wait for their request at once. The oldest are dropped first.
+    const MAX_CLIENTS: usize = 16;
+
+    /// Listen on `path`. Only the daemon's user and the members of `group` may connect to it.
+    ///
+    /// Without a group, it is the daemon's own group.
+    pub(crate) fn bind(path: &Path, group: Option<&str>) -> Result<Self> {
+        if UnixStream::connect(path).is_ok() {
+            bail!(
+                "Another instance is already listening on {}",
+                path.display()
+            );
+        }
+        // left behind by an instance that didn't exit cleanly
+        match std::fs::remove_file(path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => {
+                return Err(err).with_context(|| format!("Could not remove {}", path.display()));
+            }
+            _ => (),
+        }
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("Could not bind control socket {}", path.display()))?;
+        listener.set_nonblocking(true)?;
+        let socket = Self {
+            listener,
+            path: path.to_owned(),
+            clients: Vec::new(),
+        };
+        // requests can run commands as the daemon's user, so not just anyone may send them
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o660))?;
+        if let Some(group) = group {
+            let gid = group_id(group)?;
+            std::os::unix::fs::chown(path, None, Some(gid)).with_context(|| {
+                format!(
+                    "Could not give group {} access to {}",
+                    group,
+                    path.display()
+                )
+            })?;
+        }
+        Ok(socket)
+    }
+
+    /// Accept all the pending connections. Their requests are read once they arrive.
+    pub(crate) fn accept(&mut self) {
+        loop {
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return,
+                Err(err) => {
+                    warn!("Could not accept control connection: {}", err);
+                    return;
+                }
+            };
+            if let Err(err) = stream.set_nonblocking(true) {
+                warn!("Could not set up control connection: {}", err);
+                continue;
+            }
+            if self.clients.len() == Self::MAX_CLIENTS {
+                debug!("Too many control connections, dropping the oldest");
+                self.clients.remove(0);
+            }
+            self.clients.push(Client {
+                stream,
+                buf: Vec::new(),
+            });
+        }
+    }
+
+    /// The connections that are waiting for their request to arrive.
+    pub(crate) fn clients(&self) -> &[Client] {
+        &self.clients
+    }
+
+    /// Read from the client at `idx`, and return it along with its request once that is
+    /// complete. It is then no longer in [`Self::clients`].
+    pub(crate) fn read_client(&mut self, idx: usize) -> Option<(Client, Result<Request>)> {
+        let request = self.clients[idx].read_request()?;
+        Some((self.clients.remove(idx), request))
+    }
+}
+
+impl AsRawFd for ControlSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Send a request to the daemon, and return its response.
+pub(crate) fn send_request(path: &Path, request: &Request) -> Result<String> {
+    let mut stream = UnixStream::connect(path).with_context(|| {
+        format!(
+            "Could not connect to {}. Is asus-numpad running?",
+            path.display()
+        )
+    })?;
+    writeln!(stream, "{}", request)?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let response = response.trim_end();
+    match response.strip_prefix("error: ") {
+        Some(err) => Err(anyhow!("{}", err.to_owned())),
+        None => Ok(response.to_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_request() {
+        let requests = [
+            Request::Enable,
+            Request::Disable,
+            Request::Toggle,
+            Request::Brightness {
+                level: "half".to_owned(),
+            },
+            Request::CycleBrightness,
+            Request::ToggleCalc,
+            Request::State,
+            Request::Reload,
+        ];
+        for request in requests {
+            assert_eq!(request.to_string().parse::<Request>().unwrap(), request);
+        }
+        assert!("brightness".parse::<Request>().is_err());
+        assert!("brightness very bright".parse::<Request>().is_err());
+        assert!("enable now".parse::<Request>().is_err());
+    }
+
+    #[test]
+    fn test_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("control.sock");
+        let mut socket = ControlSocket::bind(&path, None).unwrap();
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o660
+        );
+        socket.accept();
+        assert!(socket.clients().is_empty());
+
+        // a client that doesn't send anything doesn't hold up the others
+        let mut idle = UnixStream::connect(&path).unwrap();
+        idle.write_all(b"ena").unwrap();
+        socket.accept();
+        assert!(socket.read_client(0).is_none());
+
+        let client_path = path.clone();
+        let client = std::thread::spawn(move || {
+            send_request(
+                &client_path,
+                &Request::Brightness {
+                    level: "low".to_owned(),
+                },
+            )
+        });
+        let (conn, request) = loop {
+            socket.accept();
+            if socket.clients().len() == 2 {
+                if let Some(read) = socket.read_client(1) {
+                    break read;
+                }
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        };
+        assert_eq!(
+            request.unwrap(),
+            Request::Brightness {
+                level: "low".to_owned()
+            }
+        );
+        conn.respond(&Response::Error("Numpad is disabled".to_owned()));
+        let err = client.join().unwrap().unwrap_err();
+        assert_eq!(err.to_string(), "Numpad is disabled");
+
+        idle.write_all(b"ble\n").unwrap();
+        let (_, request) = socket.read_client(0).unwrap();
+        assert_eq!(request.unwrap(), Request::Enable);
+        assert!(socket.clients().is_empty());
+
+        // a second instance must not steal the socket
+        assert!(ControlSocket::bind(&path, None).is_err());
+        drop(socket);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_group_id() {
+        assert_eq!(group_id("root").unwrap(), 0);
+        assert!(group_id("no-such-group").is_err());
+    }
+}
@@ -0,0 +1,356 @@
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc::{Receiver, Sender, channel};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use log::{debug, warn};
+use zbus::blocking::Connection;
+use zbus::blocking::connection::Builder;
+use zbus::zvariant::Value;
+use zbus::{fdo, interface};
+
+use crate::config::DbusBus;
+use crate::control::{Request, Response};
+
+pub(crate) const NAME: &str = "io.github.iamkroot.AsusNumpad";
+pub(crate) const PATH: &str = "/io/github/iamkroot/AsusNumpad";
+
+/// The state of the numpad, as exposed on the bus.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Properties {
+    pub(crate) numlock: bool,
+    pub(crate) brightness: String,
+    pub(crate) layout: String,
+}
+
+/// A method call, waiting for the daemon to carry it out.
+#[derive(Debug)]
+pub(crate) struct Call {
+    pub(crate) request: Request,
+    reply: async_channel::Sender<Response>,
+}
+
+impl Call {
+    pub(crate) fn respond(self, response: Response) {
+        // the caller is gone if the bus timed out
+        let _ = self.reply.try_send(response);
+    }
+}
+
+/// The object served on the bus.
+///
+/// zbus runs it on its own executor, so the calls are passed on to the main loop, which
+/// is woken up through a socket, and their replies are awaited without blocking it.
+struct NumpadInterface {
+    calls: Sender<Call>,
+    waker: UnixStream,
+    props: Arc<Mutex<Properties>>,
+}
+
+impl NumpadInterface {
+    async fn call(&self, request: Request) -> fdo::Result<()> {
+        let (reply, response) = async_channel::bounded(1);
+        let shutting_down = || fdo::Error::Failed("The numpad is shutting down".to_owned());
+        self.calls
+            .send(Call { request, reply })
+            .map_err(|_| shutting_down())?;
+        match (&self.waker).write(&[0]) {
+            // a full socket already has a wakeup pending
+            Ok(_) => {}
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+            Err(_) => return Err(shutting_down()),
+        }
+        match response.recv().await.map_err(|_| shutting_down())? {
+            Response::Error(msg) => Err(fdo::Error::Failed(msg)),
+            _ => Ok(()),
+        }
+    }
+
+    fn props(&self) -> Properties {
+        self.props.lock().unwrap().clone()
+    }
+}
+
+#[interface(name = "io.github.iamkroot.AsusNumpad")]
+impl NumpadInterface {
+    /// Turn the numpad on or off, like holding the numlock button does.
+    async fn toggle_numlock(&self) -> fdo::Result<()> {
+        self.call(Request::Toggle).await
+    }
+
+    async fn set_numlock(&self, numlock: bool) -> fdo::Result<()> {
+        self.call(if numlock {
+            Request::Enable
+        } else {
+            Request::Disable
+        })
+        .await
+    }
+
+    /// Switch to the next brightness, like holding the calc button does.
+    async fn cycle_brightness(&self) -> fdo::Result<()> {
+        self.call(Request::CycleBrightness).await
+    }
+
+    async fn set_brightness(&self, level: &str) -> fdo::Result<()> {
+        self.call(Request::Brightness {
+            level: level.to_owned(),
+        })
+        .await
+    }
+
+    /// Run or stop the calc command, like dragging the calc button does.
+    async fn toggle_calc(&self) -> fdo::Result<()> {
+        self.call(Request::ToggleCalc).await
+    }
+
+    #[zbus(property)]
+    fn numlock(&self) -> bool {
+        self.props().numlock
+    }
+
+    #[zbus(property)]
+    fn brightness(&self) -> String {
+        self.props().brightness
+    }
+
+    #[zbus(property)]
+    fn layout(&self) -> String {
+        self.props().layout
+    }
+}
+
+/// Exposes the numpad on D-Bus.
+#[derive(Debug)]
+pub(crate) struct DbusService {
+    conn: Connection,
+    calls: Receiver<Call>,
+    /// Readable when there are pending calls
+    wakeup: UnixStream,
+    props: Arc<Mutex<Properties>>,
+}
+
+impl DbusService {
+    /// Connect to the bus in the config, `None` if it is turned off.
+    pub(crate) fn connect(bus: DbusBus, props: Properties) -> Result<Option<Self>> {
+        let builder = match bus {
+            DbusBus::System => Builder::system()?,
+            DbusBus::Session => Builder::session()?,
+            DbusBus::Off => return Ok(None),
+        };
+        Self::serve(builder, props).map(Some)
+    }
+
+    /// Serve the numpad object, and take the well-known name.
+    pub(crate) fn serve(builder: Builder, props: Properties) -> Result<Self> {
+        let (calls_tx, calls) = channel();
+        let (wakeup, waker) = UnixStream::pair()?;
+        wakeup.set_nonblocking(true)?;
+        waker.set_nonblocking(true)?;
+        let props = Arc::new(Mutex::new(props));
+        let iface = NumpadInterface {
+            calls: calls_tx,
+            waker,
+            props: Arc::clone(&props),
+        };
+        let conn = builder
+            .serve_at(PATH, iface)?
+            .name(NAME)?
+            .build()
+            .with_context(|| format!("Could not own {} on D-Bus", NAME))?;
+        Ok(Self {
+            conn,
+            calls,
+            wakeup,
+            props,
+        })
+    }
+
+    /// Take the calls that arrived since the last time.
+    pub(crate) fn take_calls(&self) -> Vec<Call> {
+        let mut buf = [0; 64];
+        loop {
+            match (&self.wakeup).read(&mut buf) {
+                Ok(0) => break,
+                Ok(_) => continue,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    warn!("Could not read D-Bus wakeup: {}", err);
+                    break;
+                }
+            }
+        }
+        self.calls.try_iter().collect()
+    }
+
+    /// Update the properties, and tell the listeners about the ones that changed.
+    pub(crate) fn publish(&self, new: Properties) {
+        // the property getters lock it too, so don't hold it while talking to the bus
+        let old = std::mem::replace(&mut *self.props.lock().unwrap(), new.clone());
+        let mut changed = HashMap::new();
+        if new.numlock != old.numlock {
+            changed.insert("Numlock", Value::from(new.numlock));
+        }
+        if new.brightness != old.brightness {
+            changed.insert("Brightness", Value::from(new.brightness.as_str()));
+        }
+        if new.layout != old.layout {
+            changed.insert("Layout", Value::from(new.layout.as_str()));
+        }
+        if changed.is_empty() {
+            return;
+        }
+        debug!("D-Bus properties changed: {:?}", changed);
+        let body = (NAME, changed, Vec::<&str>::new());
+        if let Err(err) = self.conn.emit_signal(
+            None::<&str>,
+            PATH,
+            "org.freedesktop.DBus.Properties",
+            "PropertiesChanged",
+            &body,
+        ) {
+            warn!("Could not emit PropertiesChanged: {}", err);
+        }
+    }
+}
+
+impl AsRawFd for DbusService {
+    fn as_raw_fd(&self) -> RawFd {
+        self.wakeup.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+    use std::process::{Child, Command, Stdio};
+
+    use zbus::blocking::fdo::PropertiesProxy;
+    use zbus::names::InterfaceName;
+    use zbus::zvariant::OwnedValue;
+
+    use super::*;
+
+    /// A session bus of our own, so that the test doesn't touch the real ones.
+    struct PrivateBus {
+        daemon: Child,
+        address: String,
+    }
+
+    impl PrivateBus {
+        fn start() -> Self {
+            let mut daemon = Command::new("dbus-daemon")
+                .args(["--session", "--nofork", "--print-address"])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .expect("dbus-daemon is needed to test the D-Bus service");
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.as_mut().unwrap())
+                .read_line(&mut address)
+                .unwrap();
+            Self {
+                daemon,
+                address: address.trim().to_owned(),
+            }
+        }
+
+        fn builder(&self) -> Builder<'static> {
+            Builder::address(self.address.as_str()).unwrap()
+        }
+    }
+
+    impl Drop for PrivateBus {
+        fn drop(&mut self) {
+            let _ = self.daemon.kill();
+            let _ = self.daemon.wait();
+        }
+    }
+
+    fn wait_for_calls(service: &DbusService) -> Vec<Call> {
+        loop {
+            let calls = service.take_calls();
+            if !calls.is_empty() {
+                return calls;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_service() {
+        let bus = PrivateBus::start();
+        let props = Properties {
+            numlock: false,
+            brightness: "full".to_owned(),
+            layout: "UX433FA".to_owned(),
+        };
+        let service = DbusService::serve(bus.builder(), props.clone()).unwrap();
+
+        let client = bus.builder().build().unwrap();
+        let proxy = PropertiesProxy::builder(&client)
+            .destination(NAME)
+            .unwrap()
+            .path(PATH)
+            .unwrap()
+            .build()
+            .unwrap();
+        let iface = InterfaceName::from_static_str(NAME).unwrap();
+        let get = |name| proxy.get(iface.clone(), name).unwrap();
+        assert_eq!(
+            get("Layout"),
+            OwnedValue::try_from(Value::from("UX433FA")).unwrap()
+        );
+        let mut signals = proxy.receive_properties_changed().unwrap();
+
+        // the calls block until the main loop answers them
+        let caller = std::thread::spawn({
+            let client = client.clone();
+            move || {
+                let call = |method| client.call_method(Some(NAME), PATH, Some(NAME), method, &());
+                call("ToggleNumlock").unwrap();
+                client
+                    .call_method(Some(NAME), PATH, Some(NAME), "SetBrightness", &("half",))
+                    .unwrap();
+                call("ToggleCalc").unwrap_err()
+            }
+        });
+
+        let calls = wait_for_calls(&service);
+        assert_eq!(calls.len(), 1);
+        let call = calls.into_iter().next().unwrap();
+        assert_eq!(call.request, Request::Toggle);
+        service.publish(Properties {
+            numlock: true,
+            ..props.clone()
+        });
+        call.respond(Response::Ok);
+
+        let call = wait_for_calls(&service).into_iter().next().unwrap();
+        assert_eq!(
+            call.request,
+            Request::Brightness {
+                level: "half".to_owned()
+            }
+        );
+        call.respond(Response::Ok);
+
+        let call = wait_for_calls(&service).into_iter().next().unwrap();
+        assert_eq!(call.request, Request::ToggleCalc);
+        call.respond(Response::Error("No calc here".to_owned()));
+        let err = caller.join().unwrap();
+        assert!(err.to_string().contains("No calc here"), "{}", err);
+
+        assert_eq!(get("Numlock"), OwnedValue::from(true));
+        let signal = signals.next().unwrap();
+        let args = signal.args().unwrap();
+        assert_eq!(args.interface_name().as_str(), NAME);
+        assert_eq!(
+            args.changed_properties().keys().collect::<Vec<_>>(),
+            [&"Numlock"]
+        );
+    }
+}
@@ -1,124 +1,265 @@
 use anyhow::{Context, Result, anyhow};
 use evdev_rs::{
     Device, DeviceWrapper,
-    enums::{EV_ABS, EventCode},
+    enums::{EV_ABS, EV_LED, EventCode},
+};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::OpenOptions,
+    os::unix::prelude::OpenOptionsExt,
+    path::{Path, PathBuf},
 };
-use std::{fs::OpenOptions, os::unix::prelude::OpenOptionsExt};
 
-use crate::numpad_layout::BBox;
+use crate::config::{Config, DeviceSelector};
+use crate::contacts::SlotValues;
+use crate::gestures::Resolution;
+use crate::numpad_layout::{BBox, SupportedLayout};
+use crate::palm::PalmAxes;
+use crate::proc_input::{
+    InputDeviceInfo, i2c_bus_from_path, is_internal_keyboard, keyboard_candidates,
+    parse_proc_input, touchpad_candidates,
+};
 
-fn parse_id(line: &str, search_str: &str) -> Result<u32> {
-    let pos = line
-        .find(search_str)
-        .ok_or_else(|| anyhow!("Can't find token {} in {}", search_str, line))?;
-    let start_idx = pos + search_str.len();
+/// Parse `/proc/bus/input/devices` to get all the input devices.
+fn read_proc_input() -> Result<Vec<InputDeviceInfo>> {
+    let data = std::fs::read_to_string("/proc/bus/input/devices")
+        .context("Could not read devices file")?;
+    let devices = parse_proc_input(&data).context("Could not parse devices file")?;
+    debug!("Found input devices: {:#?}", devices);
+    Ok(devices)
+}
 
-    if start_idx >= line.len() {
-        return Err(anyhow!("Search string is at the end of the line"));
-    }
+/// Find the numpad touchpad among the input devices.
+fn find_touchpad(devices: &[InputDeviceInfo]) -> Result<&InputDeviceInfo> {
+    let touchpad = *touchpad_candidates(devices)
+        .first()
+        .ok_or(anyhow!("Can't find touchpad evdev"))?;
+    info!("Using touchpad {:?}", touchpad.name);
+    Ok(touchpad)
+}
 
-    let end_idx = start_idx
-        + line[start_idx..]
-            .chars()
-            .position(|c| !c.is_numeric())
-            .unwrap_or(line.len() - start_idx);
+/// A keyboard whose numlock LED is tracked.
+pub(crate) struct Keyboard {
+    /// The N in `/dev/input/eventN`
+    pub(crate) ev_id: u32,
+    pub(crate) evdev: Device,
+    /// Built into the laptop, next to the touchpad
+    pub(crate) internal: bool,
+}
 
-    if start_idx == end_idx {
-        return Err(anyhow!("No numeric characters found"));
+impl Keyboard {
+    fn new(ev_id: u32, evdev: Device) -> Self {
+        let internal = is_internal_keyboard(evdev.bustype(), evdev.phys().unwrap_or_default());
+        Self {
+            ev_id,
+            evdev,
+            internal,
+        }
     }
 
-    Ok(line[start_idx..end_idx].parse()?)
+    /// Open the evdev if it is a keyboard with a numlock LED.
+    ///
+    /// Used for keyboards that are plugged in later.
+    pub(crate) fn open_if_has_numlock(ev_id: u32) -> Option<Self> {
+        let evdev = open_input_evdev(ev_id).ok()?;
+        if !evdev.has(EventCode::EV_LED(EV_LED::LED_NUML)) {
+            return None;
+        }
+        info!("Tracking numlock on keyboard {:?}", evdev.name());
+        Some(Self::new(ev_id, evdev))
+    }
 }
 
-/// Parse `/proc/bus/input/devices` to find the keyboard and touchpad devices.
-/// Returns the evdev handles for keybard and touchpad, along with I2C ID of touchpad.
-pub(crate) fn read_proc_input() -> Result<(u32, u32, u32)> {
-    #[derive(Debug, PartialEq, Eq)]
-    enum Detection {
-        NotDetected,
-        Parsing,
-        Finished,
+/// Open the main keyboard whose numlock LED will be tracked.
+///
+/// Each candidate is probed through evdev, and only the ones that expose `LED_NUML`
+/// are accepted. If there are several, the internal keyboard is preferred.
+fn open_keyboard(devices: &[InputDeviceInfo]) -> Result<Keyboard> {
+    let mut accepted = Vec::new();
+    let mut rejections = Vec::new();
+    for candidate in keyboard_candidates(devices) {
+        // candidates are guaranteed to have an event ID
+        let ev_id = candidate.event_id().unwrap();
+        let reason = match open_input_evdev(ev_id) {
+            Ok(dev) if dev.has(EventCode::EV_LED(EV_LED::LED_NUML)) => {
+                debug!(
+                    "Keyboard candidate {:?} (event{}) accepted",
+                    candidate.name, ev_id
+                );
+                accepted.push((candidate, dev));
+                continue;
+            }
+            Ok(_) => "does not expose LED_NUML".to_owned(),
+            Err(err) => format!("{:#}", err),
+        };
+        info!(
+            "Rejected keyboard candidate {:?} (event{}): {}",
+            candidate.name, ev_id, reason
+        );
+        rejections.push(format!("{} (event{}): {}", candidate.name, ev_id, reason));
+    }
+    if accepted.is_empty() {
+        return Err(if rejections.is_empty() {
+            anyhow!("Can't find keyboard evdev")
+        } else {
+            anyhow!(
+                "Can't find a keyboard with a numlock LED. Rejected candidates: {}",
+                rejections.join("; ")
+            )
+        });
     }
-    let mut touchpad_detection = Detection::NotDetected;
-    let mut keyboard_detection = Detection::NotDetected;
+    let idx = accepted
+        .iter()
+        .position(|(info, _)| info.is_internal_keyboard())
+        .unwrap_or(0);
+    let (info, evdev) = accepted.swap_remove(idx);
+    info!("Using keyboard {:?}", info.name);
+    // with no internal keyboard, typing on this one mustn't block the numpad
+    Ok(Keyboard {
+        ev_id: info.event_id().unwrap(),
+        evdev,
+        internal: info.is_internal_keyboard(),
+    })
+}
 
-    let mut touchpad_i2c_id: Option<u32> = None;
-    let mut touchpad_ev_id: Option<u32> = None;
-    let mut keyboard_ev_id: Option<u32> = None;
+/// Open all the other keyboards which have a numlock LED, like external ones.
+fn open_other_keyboards(devices: &[InputDeviceInfo], skip: &[u32]) -> Vec<Keyboard> {
+    devices
+        .iter()
+        .filter(|dev| dev.has_led(EV_LED::LED_NUML))
+        .filter_map(|dev| dev.event_id())
+        .filter(|ev_id| !skip.contains(ev_id))
+        .filter_map(Keyboard::open_if_has_numlock)
+        .collect()
+}
 
-    let data = std::fs::read_to_string("/proc/bus/input/devices")
-        .context("Could not read devices file")?;
+/// Resolve the path to an evdev node, or a symlink to it, into the N of `/dev/input/eventN`.
+fn event_id_from_path(path: &Path) -> Result<u32> {
+    let real_path = path
+        .canonicalize()
+        .with_context(|| format!("Could not resolve {}", path.display()))?;
+    real_path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_prefix("event"))
+        .and_then(|id| id.parse().ok())
+        .ok_or_else(|| anyhow!("{} is not an evdev node", real_path.display()))
+}
 
-    for line in data.lines() {
-        match touchpad_detection {
-            // FIXME: Should give priority to ASUE,ASUP etc. before ELAN
-            // In general, need to parse the entire thing, and use proper filtering
-            Detection::NotDetected => {
-                if line.starts_with("N:")
-                    && (line.contains("Name=\"ASUE")
-                        || line.contains("Name=\"ELAN")
-                        || line.contains("Name=\"ASUP")
-                        || line.contains("Name=\"ASCP"))
-                    && line.contains("Touchpad")
-                {
-                    touchpad_detection = Detection::Parsing;
-                    continue;
-                }
-            }
-            Detection::Parsing => {
-                if line.starts_with("S:") {
-                    touchpad_i2c_id =
-                        Some(parse_id(line, "i2c-").context("Could not parse u32 ID")?);
-                    continue;
-                } else if line.starts_with("H:") {
-                    touchpad_ev_id =
-                        Some(parse_id(line, "event").context("Could not parse u32 ID")?);
-                    continue;
-                } else if line.is_empty() {
-                    // reset since we reached end of device info
-                    touchpad_detection = Detection::NotDetected;
-                }
-                if touchpad_i2c_id.is_some() && touchpad_ev_id.is_some() {
-                    touchpad_detection = Detection::Finished;
-                }
-            }
-            _ => (),
+/// Find the input device chosen by the selector.
+///
+/// Returns the evdev ID, along with the device's info if it is listed in `devices`.
+fn select_input_device<'a>(
+    devices: &'a [InputDeviceInfo],
+    selector: &DeviceSelector,
+) -> Result<(u32, Option<&'a InputDeviceInfo>)> {
+    match selector {
+        DeviceSelector::Path(path) => {
+            let ev_id = event_id_from_path(path)?;
+            let info = devices.iter().find(|dev| dev.event_id() == Some(ev_id));
+            Ok((ev_id, info))
         }
+        DeviceSelector::Name { name } => devices
+            .iter()
+            .find(|dev| &dev.name == name && dev.event_id().is_some())
+            .map(|dev| (dev.event_id().unwrap(), Some(dev)))
+            .ok_or_else(|| anyhow!("No input device named {:?}", name)),
+    }
+}
 
-        match keyboard_detection {
-            Detection::NotDetected => {
-                if line.starts_with("N:")
-                    && (line.contains("Name=\"AT Translated Set 2 keyboard")
-                        || ((line.contains("Name=\"ASUE") || line.contains("Name=\"Asus"))
-                            && line.contains("Keyboard")))
-                {
-                    keyboard_detection = Detection::Parsing;
-                    continue;
-                }
-            }
-            Detection::Parsing => {
-                if line.starts_with("H:") {
-                    keyboard_ev_id =
-                        Some(parse_id(line, "event").context("Could not parse u32 ID")?);
-                    // TODO: We should verify that the device actually supports KEY_NUMLOCK using evdev
-                    keyboard_detection = Detection::Finished;
+/// Find the N of the `/dev/i2c-N` bus chosen by the selector.
+///
+/// Names are matched against both the I2C adapters and the devices connected to them,
+/// so the touchpad's I2C name (eg. `ASUE1200:00`) can be used directly.
+fn select_i2c_bus(sysfs_root: &Path, selector: &DeviceSelector) -> Result<u32> {
+    match selector {
+        DeviceSelector::Path(path) => {
+            let real_path = path
+                .canonicalize()
+                .with_context(|| format!("Could not resolve {}", path.display()))?;
+            real_path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_prefix("i2c-"))
+                .and_then(|id| id.parse().ok())
+                .ok_or_else(|| anyhow!("{} is not an I2C bus", real_path.display()))
+        }
+        DeviceSelector::Name { name } => {
+            let dir = sysfs_root.join("bus/i2c/devices");
+            let entries = std::fs::read_dir(&dir)
+                .with_context(|| format!("Could not read {}", dir.display()))?;
+            for entry in entries.flatten() {
+                let entry_path: PathBuf = entry.path();
+                let matches = std::fs::read_to_string(entry_path.join("name"))
+                    .is_ok_and(|entry_name| entry_name.trim() == name);
+                if !matches {
                     continue;
-                } else if line.is_empty() {
-                    // reset since we reached end of device info
-                    keyboard_detection = Detection::NotDetected;
                 }
+                let real_path = entry_path.canonicalize()?;
+                return real_path
+                    .to_str()
+                    .and_then(i2c_bus_from_path)
+                    .ok_or_else(|| anyhow!("{} is not on an I2C bus", real_path.display()));
             }
-            _ => (),
-        }
-        if touchpad_detection == Detection::Finished && keyboard_detection == Detection::Finished {
-            break;
+            Err(anyhow!("No I2C bus or device named {:?}", name))
         }
     }
-    Ok((
-        keyboard_ev_id.ok_or(anyhow!("Can't find keyboard evdev"))?,
-        touchpad_ev_id.ok_or(anyhow!("Can't find touchpad evdev"))?,
-        touchpad_i2c_id.ok_or(anyhow!("Can't find touchpad I2C ID"))?,
-    ))
+}
+
+/// The devices used by the numpad.
+pub(crate) struct NumpadDevices {
+    pub(crate) touchpad: Device,
+    /// The main keyboard comes first
+    pub(crate) keyboards: Vec<Keyboard>,
+    /// `None` if the touchpad isn't on an I2C bus, and none is pinned
+    pub(crate) i2c_id: Option<u32>,
+}
+
+/// Open the devices pinned in the config, and autodetect the rest.
+pub(crate) fn open_devices(config: &Config) -> Result<NumpadDevices> {
+    let devices = read_proc_input().context("Couldn't get proc input devices")?;
+
+    let (touchpad_ev_id, touchpad_info) = match config.touchpad_device() {
+        Some(selector) => {
+            select_input_device(&devices, selector).context("Can't find the pinned touchpad")?
+        }
+        None => {
+            let touchpad = find_touchpad(&devices)?;
+            // candidates are guaranteed to have an event ID
+            (touchpad.event_id().unwrap(), Some(touchpad))
+        }
+    };
+    let touchpad = open_input_evdev(touchpad_ev_id)?;
+
+    let keyboard = match config.keyboard_device() {
+        Some(selector) => {
+            let (ev_id, _) = select_input_device(&devices, selector)
+                .context("Can't find the pinned keyboard")?;
+            let evdev = open_input_evdev(ev_id)?;
+            if !evdev.has(EventCode::EV_LED(EV_LED::LED_NUML)) {
+                warn!("Pinned keyboard event{} does not expose LED_NUML", ev_id);
+            }
+            Keyboard::new(ev_id, evdev)
+        }
+        None => open_keyboard(&devices)?,
+    };
+    let mut keyboards = open_other_keyboards(&devices, &[touchpad_ev_id, keyboard.ev_id]);
+    keyboards.insert(0, keyboard);
+
+    let i2c_id = match config.i2c_device() {
+        Some(selector) => Some(
+            select_i2c_bus(Path::new("/sys"), selector).context("Can't find the pinned I2C bus")?,
+        ),
+        // only needed for the I2C backlight, which reports it missing
+        None => touchpad_info.and_then(InputDeviceInfo::i2c_id),
+    };
+
+    Ok(NumpadDevices {
+        touchpad,
+        keyboards,
+        i2c_id,
+    })
 }
 
 pub(crate) fn open_input_evdev(evdev_id: u32) -> Result<Device> {
@@ -130,9 +271,15 @@ pub(crate) fn open_input_evdev(evdev_id: u32) -> Result<Device> {
         .open(&path)
         .with_context(|| path.clone())
         .context("Couldn't open device event handle")?;
-    Device::new_from_file(file)
+    let evdev = Device::new_from_file(file)
+        .with_context(|| path.clone())
+        .context("Unable to open evdev device")?;
+    // timestamps are compared with our own clock for the timeouts
+    evdev
+        .set_clock_id(libc::CLOCK_MONOTONIC)
         .with_context(|| path)
-        .context("Unable to open evdev device")
+        .context("Unable to set evdev clock")?;
+    Ok(evdev)
 }
 
 pub(crate) fn get_touchpad_bbox(touchpad_evdev: &Device) -> Result<BBox> {
@@ -149,3 +296,159 @@ pub(crate) fn get_touchpad_bbox(touchpad_evdev: &Device) -> Result<BBox> {
         absy.maximum,
     ))
 }
+
+pub(crate) fn get_touchpad_resolution(touchpad_evdev: &Device) -> Result<Resolution> {
+    let absx = touchpad_evdev
+        .abs_info(&EventCode::EV_ABS(EV_ABS::ABS_X))
+        .ok_or(anyhow!("Could not get touchpad x resolution"))?;
+    let absy = touchpad_evdev
+        .abs_info(&EventCode::EV_ABS(EV_ABS::ABS_Y))
+        .ok_or(anyhow!("Could not get touchpad y resolution"))?;
+    Ok(Resolution {
+        x: absx.resolution,
+        y: absy.resolution,
+    })
+}
+
+/// Probe the axes used to tell palms apart from fingers.
+pub(crate) fn get_touchpad_palm_axes(touchpad_evdev: &Device) -> PalmAxes {
+    let max = |axis| {
+        touchpad_evdev
+            .abs_info(&EventCode::EV_ABS(axis))
+            .map(|info| info.maximum)
+    };
+    PalmAxes {
+        touch_major: max(EV_ABS::ABS_MT_TOUCH_MAJOR),
+        width_major: max(EV_ABS::ABS_MT_WIDTH_MAJOR),
+        pressure: max(EV_ABS::ABS_MT_PRESSURE),
+    }
+}
+
+/// The multitouch slot that the touchpad will report changes for, until it sends `ABS_MT_SLOT`.
+pub(crate) fn get_touchpad_slot(touchpad_evdev: &Device) -> i32 {
+    touchpad_evdev
+        .event_value(&EventCode::EV_ABS(EV_ABS::ABS_MT_SLOT))
+        .unwrap_or_default()
+}
+
+/// The last values of each multitouch slot, which the kernel won't report again if they
+/// don't change.
+pub(crate) fn get_touchpad_slot_values(touchpad_evdev: &Device) -> Vec<SlotValues> {
+    let num_slots = touchpad_evdev.num_slots().unwrap_or_default().max(0) as u32;
+    (0..num_slots)
+        .map(|slot| {
+            let value = |axis| {
+                touchpad_evdev
+                    .slot_value(slot, &EventCode::EV_ABS(axis))
+                    .unwrap_or_default()
+            };
+            SlotValues {
+                x: value(EV_ABS::ABS_MT_POSITION_X),
+                y: value(EV_ABS::ABS_MT_POSITION_Y),
+                touch_major: value(EV_ABS::ABS_MT_TOUCH_MAJOR),
+                width_major: value(EV_ABS::ABS_MT_WIDTH_MAJOR),
+                pressure: value(EV_ABS::ABS_MT_PRESSURE),
+                tool_type: value(EV_ABS::ABS_MT_TOOL_TYPE),
+            }
+        })
+        .collect()
+}
+
+/// Everything about the touchpad that the numpad logic needs.
+///
+/// Stored in recordings, so that they can be replayed without the touchpad.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct TouchpadInfo {
+    pub(crate) name: String,
+    /// The laptop model, if it was detected
+    pub(crate) detected: Option<SupportedLayout>,
+    pub(crate) bbox: BBox,
+    pub(crate) resolution: Resolution,
+    pub(crate) palm_axes: PalmAxes,
+    pub(crate) cur_slot: i32,
+    /// The values of each multitouch slot
+    #[serde(default)]
+    pub(crate) slots: Vec<SlotValues>,
+}
+
+impl TouchpadInfo {
+    pub(crate) fn read(touchpad_evdev: &Device, detected: Option<SupportedLayout>) -> Result<Self> {
+        Ok(Self {
+            name: touchpad_evdev.name().unwrap_or_default().to_owned(),
+            detected,
+            bbox: get_touchpad_bbox(touchpad_evdev)?,
+            resolution: get_touchpad_resolution(touchpad_evdev)?,
+            palm_axes: get_touchpad_palm_axes(touchpad_evdev),
+            cur_slot: get_touchpad_slot(touchpad_evdev),
+            slots: get_touchpad_slot_values(touchpad_evdev),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+
+    #[test]
+    fn test_event_id_from_symlink() {
+        let dir = tempfile::tempdir().unwrap();
+        let node = dir.path().join("event7");
+        std::fs::write(&node, "").unwrap();
+        let link = dir.path().join("platform-i8042-serio-0-event-kbd");
+        symlink(&node, &link).unwrap();
+        assert_eq!(event_id_from_path(&link).unwrap(), 7);
+        assert!(event_id_from_path(&dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn test_select_by_name() {
+        let data = std::fs::read_to_string(
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/proc_input/gx701.txt"),
+        )
+        .unwrap();
+        let devices = parse_proc_input(&data).unwrap();
+        let selector = DeviceSelector::Name {
+            name: "AT Translated Set 2 keyboard".to_owned(),
+        };
+        let (ev_id, info) = select_input_device(&devices, &selector).unwrap();
+        assert_eq!(ev_id, 3);
+        assert_eq!(info.unwrap().name, "AT Translated Set 2 keyboard");
+        let selector = DeviceSelector::Name {
+            name: "Nonexistent".to_owned(),
+        };
+        assert!(select_input_device(&devices, &selector).is_err());
+    }
+
+    #[test]
+    fn test_select_i2c_bus() {
+        let sysfs = tempfile::tempdir().unwrap();
+        let adapter = sysfs
+            .path()
+            .join("devices/pci0000:00/i2c_designware.0/i2c-2");
+        let client = adapter.join("i2c-ASUE1200:00");
+        std::fs::create_dir_all(&client).unwrap();
+        std::fs::write(adapter.join("name"), "Synopsys DesignWare I2C adapter\n").unwrap();
+        std::fs::write(client.join("name"), "ASUE1200:00\n").unwrap();
+        let bus_dir = sysfs.path().join("bus/i2c/devices");
+        std::fs::create_dir_all(&bus_dir).unwrap();
+        symlink(&adapter, bus_dir.join("i2c-2")).unwrap();
+        symlink(&client, bus_dir.join("i2c-ASUE1200:00")).unwrap();
+
+        let select = |name: &str| {
+            select_i2c_bus(
+                sysfs.path(),
+                &DeviceSelector::Name {
+                    name: name.to_owned(),
+                },
+            )
+        };
+        assert_eq!(select("Synopsys DesignWare I2C adapter").unwrap(), 2);
+        assert_eq!(select("ASUE1200:00").unwrap(), 2);
+        assert!(select("ELAN1200:00").is_err());
+
+        let by_path = DeviceSelector::Path(bus_dir.join("i2c-2"));
+        assert_eq!(select_i2c_bus(sysfs.path(), &by_path).unwrap(), 2);
+    }
+}
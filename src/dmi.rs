@@ -0,0 +1,137 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use log::debug;
+
+use crate::numpad_layout::SupportedLayout;
+
+/// A known laptop model, matched by a substring of its DMI product/board name.
+struct Model {
+    pattern: &'static str,
+    layout: SupportedLayout,
+}
+
+/// Table of known models.
+///
+/// Asus encodes the model in both the product and board names, usually with a suffix
+/// for the exact SKU (eg. `ZenBook UX433FA_UX433FA`, `GX701GXR`), so substring matches
+/// are sufficient. More specific patterns must come first.
+const MODELS: &[Model] = &[
+    Model {
+        pattern: "UX433FA",
+        layout: SupportedLayout::UX433FA,
+    },
+    // Also matches the UM433IA variant
+    Model {
+        pattern: "M433IA",
+        layout: SupportedLayout::M433IA,
+    },
+    Model {
+        pattern: "UX581",
+        layout: SupportedLayout::UX581,
+    },
+    Model {
+        pattern: "GX701",
+        layout: SupportedLayout::GX701,
+    },
+    Model {
+        pattern: "GX531",
+        layout: SupportedLayout::GX531,
+    },
+    Model {
+        pattern: "G533",
+        layout: SupportedLayout::G533,
+    },
+];
+
+/// Identification strings of the machine, as exposed by the kernel in sysfs.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct DmiInfo {
+    pub(crate) product_name: String,
+    pub(crate) board_name: String,
+}
+
+impl DmiInfo {
+    /// Read the DMI info from the sysfs tree mounted at `sysfs_root` (normally `/sys`).
+    pub(crate) fn read(sysfs_root: &Path) -> Result<Self> {
+        let dmi_dir = sysfs_root.join("class/dmi/id");
+        let read = |name: &str| {
+            let path = dmi_dir.join(name);
+            std::fs::read_to_string(&path)
+                .map(|s| s.trim().to_owned())
+                .with_context(|| format!("Could not read {}", path.display()))
+        };
+        Ok(Self {
+            product_name: read("product_name")?,
+            board_name: read("board_name")?,
+        })
+    }
+}
+
+fn find_model(name: &str) -> Option<SupportedLayout> {
+    let name = name.to_ascii_uppercase();
+    MODELS
+        .iter()
+        .find(|model| name.contains(model.pattern))
+        .map(|model| model.layout)
+}
+
+/// Find the layout for the given machine.
+///
+/// The product name is checked first, then the board name. As a last resort, the
+/// touchpad's name is checked, since a few models carry the model number in it.
+pub(crate) fn detect_layout(dmi: &DmiInfo, touchpad_name: Option<&str>) -> Option<SupportedLayout> {
+    let layout = find_model(&dmi.product_name)
+        .or_else(|| find_model(&dmi.board_name))
+        .or_else(|| touchpad_name.and_then(find_model));
+    debug!(
+        "Detected layout {:?} for {:?}, touchpad {:?}",
+        layout, dmi, touchpad_name
+    );
+    layout
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/sysfs")
+            .join(name)
+    }
+
+    fn detect_fixture(name: &str) -> Option<SupportedLayout> {
+        let dmi = DmiInfo::read(&fixture(name)).unwrap();
+        detect_layout(&dmi, None)
+    }
+
+    #[test]
+    fn test_detect_from_product_name() {
+        assert_eq!(detect_fixture("ux433fa"), Some(SupportedLayout::UX433FA));
+        assert_eq!(detect_fixture("um433ia"), Some(SupportedLayout::M433IA));
+        assert_eq!(detect_fixture("gx701gxr"), Some(SupportedLayout::GX701));
+    }
+
+    #[test]
+    fn test_detect_from_board_name() {
+        assert_eq!(detect_fixture("ux581gv"), Some(SupportedLayout::UX581));
+    }
+
+    #[test]
+    fn test_detect_unknown() {
+        assert_eq!(detect_fixture("ux425ea"), None);
+        let dmi = DmiInfo::read(&fixture("ux425ea")).unwrap();
+        assert_eq!(
+            detect_layout(&dmi, Some("ASUE1200:00 04F3:3087 Touchpad")),
+            None
+        );
+    }
+
+    #[test]
+    fn test_missing_dmi() {
+        assert!(DmiInfo::read(&fixture("nonexistent")).is_err());
+    }
+}
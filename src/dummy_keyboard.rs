@@ -1,16 +1,57 @@
+use std::collections::BTreeSet;
 use std::io::ErrorKind::{NotFound, PermissionDenied};
 
 use anyhow::{Context, Error, Result};
 use evdev_rs::{
-    DeviceWrapper, InputEvent, TimeVal, UInputDevice, UninitDevice,
-    enums::{EV_KEY, EV_SYN, EventCode},
+    DeviceWrapper, EnableCodeData, InputEvent, TimeVal, UInputDevice, UninitDevice,
+    enums::{EV_KEY, EV_REP, EV_SYN, EventCode},
 };
-use log::trace;
+use log::{debug, trace};
+use serde::Deserialize;
 
 use crate::numpad_layout::NumpadLayout;
 
+/// Autorepeat of held numpad keys, as given in the config file.
+///
+/// The kernel generates the repeats, the same way it does for a real keyboard.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct AutorepeatConfig {
+    enabled: bool,
+    /// How long a key must be held before it starts repeating, in ms
+    delay_ms: u32,
+    /// Repeats per second. 0 disables the autorepeat, like `enabled = false`.
+    rate: u32,
+}
+
+impl Default for AutorepeatConfig {
+    fn default() -> Self {
+        // off, since the numpad never repeated before. The timings are the kernel's defaults.
+        Self {
+            enabled: false,
+            delay_ms: 250,
+            rate: 30,
+        }
+    }
+}
+
+impl AutorepeatConfig {
+    fn repeats(&self) -> bool {
+        self.enabled && self.rate > 0
+    }
+
+    /// Time between repeats, in ms. Only meaningful if it [repeats](Self::repeats).
+    fn period_ms(&self) -> u32 {
+        // a period of 0 would stop the repeats instead
+        (1000 / self.rate.max(1)).max(1)
+    }
+}
+
 pub(crate) struct DummyKeyboard {
     pub(crate) udev: UInputDevice,
+    /// The keys that the device was created with
+    keys: BTreeSet<EV_KEY>,
+    autorepeat: AutorepeatConfig,
 }
 
 impl std::fmt::Debug for DummyKeyboard {
@@ -22,18 +63,25 @@ impl std::fmt::Debug for DummyKeyboard {
 }
 
 impl DummyKeyboard {
-    pub(crate) fn new(layout: &NumpadLayout) -> Result<Self> {
+    pub(crate) fn new(layout: &NumpadLayout, autorepeat: &AutorepeatConfig) -> Result<Self> {
         let dev = UninitDevice::new().context("Unable to create uninit evdev device.")?;
         dev.set_name("asus_numpad");
-        let default_keys = [EV_KEY::KEY_LEFTSHIFT, EV_KEY::KEY_NUMLOCK, EV_KEY::KEY_CALC];
-        for key in default_keys {
-            dev.enable(EventCode::EV_KEY(key))
+        let keys = Self::keys(layout);
+        for key in &keys {
+            dev.enable(EventCode::EV_KEY(*key))
                 .with_context(|| format!("Unable to enable key {:?}", key))?;
         }
-        for row in layout.keys().iter() {
-            for key in row {
-                dev.enable(EventCode::EV_KEY(*key))
-                    .with_context(|| format!("Unable to enable key {:?}", key))?;
+        let repeat_info = [
+            (EV_REP::REP_DELAY, autorepeat.delay_ms),
+            (EV_REP::REP_PERIOD, autorepeat.period_ms()),
+        ];
+        if autorepeat.repeats() {
+            for (code, value) in repeat_info {
+                dev.enable_event_code(
+                    &EventCode::EV_REP(code),
+                    Some(EnableCodeData::RepInfo(value as i32)),
+                )
+                .context("Unable to enable autorepeat")?;
             }
         }
         let udev = UInputDevice::create_from_device(&dev).map_err(|err| {
@@ -50,7 +98,38 @@ impl DummyKeyboard {
             Error::new(err).context(context)
         })?;
 
-        Ok(Self { udev })
+        if autorepeat.repeats() {
+            // uinput only enables EV_REP with the default delays, they need to be set afterwards
+            debug!("Setting autorepeat to {:?}", autorepeat);
+            for (code, value) in repeat_info {
+                udev.write_event(&InputEvent::new(
+                    &Self::DUMMY_TIMEVAL,
+                    &EventCode::EV_REP(code),
+                    value as i32,
+                ))
+                .context("Unable to set autorepeat")?;
+            }
+        }
+
+        Ok(Self {
+            udev,
+            keys,
+            autorepeat: autorepeat.clone(),
+        })
+    }
+
+    /// The keys that need to be enabled for the layout.
+    fn keys(layout: &NumpadLayout) -> BTreeSet<EV_KEY> {
+        let default_keys = [EV_KEY::KEY_LEFTSHIFT, EV_KEY::KEY_NUMLOCK, EV_KEY::KEY_CALC];
+        default_keys
+            .into_iter()
+            .chain(layout.keys().iter().flatten().copied())
+            .collect()
+    }
+
+    /// Whether the device can be kept for the given layout, or needs to be created again.
+    pub(crate) fn fits(&self, layout: &NumpadLayout, autorepeat: &AutorepeatConfig) -> bool {
+        self.keys == Self::keys(layout) && self.autorepeat == *autorepeat
     }
 }
 
@@ -151,3 +230,32 @@ impl KeyEvents for DummyKeyboard {
             .expect("No syn");
     }
 }
+
+#[test]
+fn test_autorepeat_period() {
+    assert!(!AutorepeatConfig::default().repeats());
+    let config: AutorepeatConfig = toml::from_str("enabled = true\nrate = 40").unwrap();
+    assert!(config.repeats());
+    assert_eq!(config.delay_ms, 250);
+    assert_eq!(config.period_ms(), 25);
+    let config: AutorepeatConfig = toml::from_str("enabled = true\nrate = 0").unwrap();
+    assert!(!config.repeats());
+    let config: AutorepeatConfig = toml::from_str("enabled = true\nrate = 5000").unwrap();
+    assert_eq!(config.period_ms(), 1);
+}
+
+#[test]
+fn test_keys() {
+    use crate::numpad_layout::{BBox, LayoutConfig, SupportedLayout};
+
+    let keys = |layout| {
+        let bbox = BBox::new(0, 1000, 0, 1000);
+        DummyKeyboard::keys(
+            &NumpadLayout::from_config(&LayoutConfig::Preset(layout), None, bbox).unwrap(),
+        )
+    };
+    let ux433fa = keys(SupportedLayout::UX433FA);
+    assert!(ux433fa.contains(&EV_KEY::KEY_NUMLOCK));
+    assert!(ux433fa.contains(&EV_KEY::KEY_KP7));
+    assert_ne!(ux433fa, keys(SupportedLayout::UX581));
+}
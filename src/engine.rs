@@ -0,0 +1,1413 @@
+use anyhow::{Context, Result, anyhow};
+use evdev_rs::{
+    InputEvent, TimeVal,
+    enums::{EV_KEY, EV_LED, EV_MSC, EV_SYN, EventCode},
+};
+use log::{debug, info, trace};
+use serde::Serialize;
+
+use crate::Point;
+use crate::brightness::{Brightness, BrightnessLevels};
+use crate::config::{Config, IdleAction};
+use crate::contacts::{Contacts, Frame};
+use crate::control::Request;
+use crate::devices::TouchpadInfo;
+use crate::fade::Fade;
+use crate::gestures::Gestures;
+use crate::idle::IdleTimer;
+use crate::numpad_layout::NumpadLayout;
+use crate::palm::PalmThresholds;
+use crate::state_file::SavedState;
+use crate::util::{Clock, CustomDuration, ElapsedSince};
+
+/// Something that the engine wants done to the outside world.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Action {
+    /// Press these keys on the dummy keyboard, together
+    KeyDown(Vec<EV_KEY>),
+    /// Release these keys on the dummy keyboard, together
+    KeyUp(Vec<EV_KEY>),
+    /// Press and release numlock, to toggle it on the system
+    ToggleNumlock,
+    SetBrightness(Brightness),
+    /// Grab the touchpad, so that its events don't reach the pointer
+    Grab,
+    Ungrab,
+    /// Run the calc start command
+    StartCalc,
+    /// Run the calc stop command
+    StopCalc,
+}
+
+#[derive(PartialEq, Default, Debug, Clone, Copy)]
+enum FingerState {
+    #[default]
+    Lifted,
+    TouchStart,
+    Touching,
+}
+
+/// Represents the key being pressed currently
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CurKey {
+    #[default]
+    None,
+    Numlock,
+    Calc,
+    /// A key on the actual numpad bbox
+    Numpad(EV_KEY),
+}
+
+impl CurKey {
+    #[inline]
+    fn reset(&mut self) {
+        *self = Self::None;
+    }
+}
+
+#[derive(Debug)]
+struct TouchpadState {
+    pos: Point,
+    finger_state: FingerState,
+    numlock: bool,
+    cur_key: CurKey,
+    tap_started_at: TimeVal,
+    tap_start_pos: Point,
+    tapped_outside_numlock_bbox: bool,
+    finger_dragged_too_much: bool,
+    dragged_finger_lifted_at: TimeVal,
+    brightness: Brightness,
+    calc_open: bool,
+    /// The backlight was dimmed because the numpad went idle
+    idle_dimmed: bool,
+    /// Last key press on an internal keyboard, for disable-while-typing
+    last_typed_at: Option<TimeVal>,
+    /// Anything was touching the pad at the end of the last frame
+    fingers_down: bool,
+}
+
+impl TouchpadState {
+    #[inline]
+    fn toggle_numlock(&mut self) -> bool {
+        self.numlock = !self.numlock;
+        self.numlock
+    }
+}
+
+impl Default for TouchpadState {
+    fn default() -> Self {
+        Self {
+            pos: Default::default(),
+            finger_state: Default::default(),
+            numlock: false,
+            cur_key: Default::default(),
+            tap_started_at: TimeVal {
+                tv_sec: 0,
+                tv_usec: 0,
+            },
+            tap_start_pos: Default::default(),
+            tapped_outside_numlock_bbox: false,
+            finger_dragged_too_much: false,
+            dragged_finger_lifted_at: TimeVal {
+                tv_sec: 0,
+                tv_usec: 0,
+            },
+            // set to the configured default by the engine
+            brightness: Brightness::off(),
+            calc_open: false,
+            idle_dimmed: false,
+            last_typed_at: None,
+            fingers_down: false,
+        }
+    }
+}
+
+/// The state of the numpad, as reported to the control clients.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct Status {
+    pub(crate) numlock: bool,
+    pub(crate) brightness: Brightness,
+    pub(crate) calc_open: bool,
+}
+
+/// Whether the touchpad is grabbed, to keep numpad touches away from the pointer.
+///
+/// It is taken as soon as a touch presses a key, but only released once nothing is touching
+/// the pad. Otherwise, the compositor sees a contact that it never saw start, which makes the
+/// pointer jump.
+#[derive(Debug)]
+struct GrabState {
+    grabbed: bool,
+    /// The pointer is being used, release the grab once all fingers lift
+    release_pending: bool,
+    /// Releases the grab if the numpad isn't touched again for a while, so that
+    /// quick double taps are still swallowed, but the pointer is usable soon after.
+    linger: IdleTimer,
+}
+
+/// The numpad's state machine.
+///
+/// It doesn't touch any devices: it is fed the input events and asked for the timeouts,
+/// and returns the [`Action`]s to perform in response.
+#[derive(Debug)]
+pub(crate) struct Engine<C> {
+    layout: NumpadLayout,
+    gestures: Gestures,
+    levels: BrightnessLevels,
+    contacts: Contacts,
+    config: Config,
+    state: TouchpadState,
+    grab: GrabState,
+    idle_timer: IdleTimer,
+    fade: Fade,
+    clock: C,
+    actions: Vec<Action>,
+}
+
+/// The parts of the config that depend on the touchpad, or need to be validated.
+type Resolved = (
+    NumpadLayout,
+    Gestures,
+    BrightnessLevels,
+    Option<CustomDuration>,
+);
+
+impl<C: Clock> Engine<C> {
+    fn new(
+        (layout, gestures, levels, idle_timeout): Resolved,
+        contacts: Contacts,
+        config: Config,
+        clock: C,
+    ) -> Self {
+        let grab = GrabState {
+            grabbed: false,
+            release_pending: false,
+            linger: IdleTimer::new(Some(gestures.grab_linger)),
+        };
+        let state = TouchpadState {
+            brightness: levels.default_level(),
+            ..Default::default()
+        };
+        Self {
+            layout,
+            gestures,
+            levels,
+            contacts,
+            idle_timer: IdleTimer::new(idle_timeout),
+            fade: Fade::new(config.brightness().fade()),
+            config,
+            state,
+            grab,
+            clock,
+            actions: Vec::new(),
+        }
+    }
+
+    /// Set up the engine for the given touchpad, as the config says.
+    pub(crate) fn from_config(config: Config, touchpad: &TouchpadInfo, clock: C) -> Result<Self> {
+        let resolved = Self::resolve(&config, touchpad)?;
+        let contacts = Self::new_contacts(&config, &resolved.1, touchpad);
+        Ok(Self::new(resolved, contacts, config, clock))
+    }
+
+    /// Resolve the layout, the gestures, the brightness levels and the idle timeout of the
+    /// config for the given touchpad.
+    fn resolve(config: &Config, touchpad: &TouchpadInfo) -> Result<Resolved> {
+        let gestures = Gestures::resolve(config.gestures(), &touchpad.bbox, touchpad.resolution)
+            .context("Invalid gesture config")?;
+        debug!("Gestures: {:?}", gestures);
+        let levels =
+            BrightnessLevels::resolve(config.brightness()).context("Invalid brightness config")?;
+        let idle_timeout = config.idle_timeout()?;
+        let layout = match config.layout() {
+            Some(layout) => NumpadLayout::from_config(layout, touchpad.detected, touchpad.bbox)?,
+            None => {
+                let detected = touchpad.detected.ok_or_else(|| {
+                    anyhow!(
+                        "Could not detect the laptop model. \
+                        Please specify the layout in the config file."
+                    )
+                })?;
+                NumpadLayout::from_spec(&format!("{:?}", detected), detected.spec(), touchpad.bbox)?
+            }
+        };
+        Ok((layout, gestures, levels, idle_timeout))
+    }
+
+    /// Switch to a new config, keeping the numlock, brightness and calc state.
+    ///
+    /// The config is checked first, so the current one stays if it is invalid.
+    pub(crate) fn reload(
+        &mut self,
+        config: Config,
+        touchpad: &TouchpadInfo,
+    ) -> Result<Vec<Action>> {
+        let (layout, gestures, levels, idle_timeout) = Self::resolve(&config, touchpad)?;
+        // the keys might have moved, so end the current touch
+        if self.state.cur_key == CurKey::Calc {
+            self.state.cur_key.reset();
+        }
+        self.on_lift();
+        // the contacts start afresh, so they won't see the current fingers lift
+        let palm = PalmThresholds::resolve(config.palm_rejection(), &touchpad.palm_axes);
+        self.contacts.restart(gestures.extra_fingers, palm);
+        self.state.fingers_down = false;
+        self.ungrab();
+        self.grab.linger = IdleTimer::new(Some(gestures.grab_linger));
+        self.idle_timer = IdleTimer::new(idle_timeout);
+        self.fade.set_duration(config.brightness().fade());
+        self.layout = layout;
+        self.gestures = gestures;
+        self.config = config;
+        // keep the level with the same name, its value might have changed
+        let brightness = levels
+            .get(self.state.brightness.name())
+            .unwrap_or_else(|_| levels.default_level());
+        self.levels = levels;
+        if brightness != self.state.brightness {
+            self.change_brightness(brightness);
+        } else if self.state.numlock {
+            // restart the new idle timer
+            self.on_numpad_used();
+        }
+        Ok(self.take_actions())
+    }
+
+    fn new_contacts(config: &Config, gestures: &Gestures, touchpad: &TouchpadInfo) -> Contacts {
+        let palm = PalmThresholds::resolve(config.palm_rejection(), &touchpad.palm_axes);
+        Contacts::new(
+            gestures.extra_fingers,
+            palm,
+            touchpad.cur_slot,
+            &touchpad.slots,
+        )
+    }
+
+    pub(crate) fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub(crate) fn layout(&self) -> &NumpadLayout {
+        &self.layout
+    }
+
+    pub(crate) fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    pub(crate) fn status(&self) -> Status {
+        Status {
+            numlock: self.state.numlock,
+            brightness: self.state.brightness.clone(),
+            calc_open: self.state.calc_open,
+        }
+    }
+
+    /// The state to keep across restarts.
+    pub(crate) fn saved_state(&self) -> SavedState {
+        SavedState {
+            brightness: self.state.brightness.name().to_owned(),
+            numlock: self.state.numlock,
+        }
+    }
+
+    fn take_actions(&mut self) -> Vec<Action> {
+        std::mem::take(&mut self.actions)
+    }
+
+    /// Change the backlight, fading to the new brightness if enabled.
+    fn set_brightness(&mut self, brightness: Brightness) {
+        let brightness = self.fade.start(brightness, self.clock.now());
+        self.actions.push(Action::SetBrightness(brightness));
+    }
+
+    /// Restart the idle timer, and undo the dimming if it had already run out.
+    fn on_numpad_used(&mut self) {
+        self.idle_timer.reset(self.clock.now());
+        if self.state.idle_dimmed {
+            debug!("Numpad used again, restoring brightness");
+            self.state.idle_dimmed = false;
+            self.set_brightness(self.state.brightness.clone());
+        }
+    }
+
+    fn on_numlock_off(&mut self) {
+        self.idle_timer.stop();
+        self.state.idle_dimmed = false;
+        self.set_brightness(Brightness::off());
+    }
+
+    /// Called once the numpad hasn't been used for the configured idle timeout.
+    fn on_idle(&mut self) {
+        if !self.state.numlock {
+            return;
+        }
+        if self.state.finger_state != FingerState::Lifted {
+            // still being touched, wait for another timeout
+            return self.on_numpad_used();
+        }
+        match self.config.idle_action() {
+            IdleAction::NumlockOff => {
+                info!("Numpad idle, turning off numlock");
+                self.toggle_numlock();
+            }
+            IdleAction::Dim => {
+                info!("Numpad idle, dimming backlight");
+                self.state.idle_dimmed = true;
+                self.set_brightness(self.levels.dimmest());
+            }
+        }
+    }
+
+    /// Toggle numlock when user presses the numlock bbox on touchpad.
+    fn toggle_numlock(&mut self) {
+        if self.state.toggle_numlock() {
+            self.set_brightness(self.state.brightness.clone());
+            self.on_numpad_used();
+            // don't grab touchpad - allow moving pointer even if active
+        } else {
+            self.on_numlock_off();
+            // we might still be grabbing the touchpad. release it.
+            self.release_grab();
+        }
+        // Tell the system that we want to toggle the numlock
+        self.actions.push(Action::ToggleNumlock);
+    }
+
+    /// Turn the numpad on or off, as requested by a control client.
+    pub(crate) fn set_numlock(&mut self, numlock: bool) -> Vec<Action> {
+        if numlock != self.state.numlock {
+            self.toggle_numlock();
+        }
+        self.take_actions()
+    }
+
+    /// Change the brightness used while the numpad is on.
+    fn change_brightness(&mut self, brightness: Brightness) {
+        self.state.brightness = brightness;
+        if self.state.numlock {
+            if !self.state.idle_dimmed {
+                self.set_brightness(self.state.brightness.clone());
+            }
+            // sets the new brightness if it was dimmed
+            self.on_numpad_used();
+        }
+    }
+
+    /// Set the brightness used while the numpad is on, by the name of the level.
+    pub(crate) fn set_brightness_level(&mut self, name: &str) -> Result<Vec<Action>> {
+        let brightness = self.levels.get(name)?;
+        self.change_brightness(brightness);
+        Ok(self.take_actions())
+    }
+
+    /// Switch to the next brightness, like holding the calc button does.
+    pub(crate) fn cycle_brightness(&mut self) -> Vec<Action> {
+        let brightness = self.levels.next(&self.state.brightness);
+        self.change_brightness(brightness);
+        self.take_actions()
+    }
+
+    fn toggle_calc(&mut self) {
+        if !self.state.calc_open {
+            debug!("Starting calc");
+            self.actions.push(Action::StartCalc);
+        } else {
+            debug!("Stopping calc");
+            self.actions.push(Action::StopCalc);
+        }
+        self.state.calc_open = !self.state.calc_open;
+    }
+
+    /// Start or stop the calc command, like dragging the calc button does.
+    pub(crate) fn trigger_calc(&mut self) -> Vec<Action> {
+        self.toggle_calc();
+        self.take_actions()
+    }
+
+    /// Carry out a request from a control client.
+    ///
+    /// Reloading the config and reading the status are left to the caller.
+    pub(crate) fn handle_request(&mut self, request: &Request) -> Result<Vec<Action>> {
+        Ok(match request {
+            Request::Enable => self.set_numlock(true),
+            Request::Disable => self.set_numlock(false),
+            Request::Toggle => self.set_numlock(!self.state.numlock),
+            Request::Brightness { level } => self.set_brightness_level(level)?,
+            Request::CycleBrightness => self.cycle_brightness(),
+            Request::ToggleCalc => self.trigger_calc(),
+            Request::State | Request::Reload => Vec::new(),
+        })
+    }
+
+    /// Handle numlock pressed *from an external keyboard*.
+    ///
+    /// This is to keep the touchpad state in sync with system's numlock.
+    fn handle_numlock_pressed(&mut self, val: i32) {
+        if val == 0 {
+            debug!("Setting numpad off");
+            self.state.numlock = false;
+            // we might still be grabbing the touchpad. release it.
+            self.release_grab();
+            self.on_numlock_off();
+        } else {
+            debug!("Setting numpad on {}", self.state.brightness);
+            self.state.numlock = true;
+            self.set_brightness(self.state.brightness.clone());
+            self.on_numpad_used();
+        }
+        // The numlock has already been toggled on the system- no need to press
+        // the Num_Lock evkey.
+    }
+
+    /// Sync with the initial state of the numlock LED, and restore the state saved by the
+    /// last run, if any.
+    pub(crate) fn initialize_numlock(
+        &mut self,
+        init_numlock: i32,
+        saved: Option<&SavedState>,
+    ) -> Vec<Action> {
+        if let Some(saved) = saved {
+            match self.levels.get(&saved.brightness) {
+                Ok(brightness) => {
+                    debug!("Restoring brightness {}", brightness);
+                    self.state.brightness = brightness;
+                }
+                Err(err) => debug!("Not restoring brightness: {:#}", err),
+            }
+        }
+        let restore_numlock = saved
+            .filter(|_| self.config.restore_numlock())
+            .map(|saved| saved.numlock);
+        if self.config.disable_numpad() {
+            debug!("Skipping numlock init as numpad control is disabled");
+        } else if let Some(numlock) = restore_numlock {
+            debug!("Restoring numlock {}", numlock);
+            match (init_numlock != 0, numlock) {
+                // the LED will tell us once it is off
+                (true, false) => self.actions.push(Action::ToggleNumlock),
+                (false, true) => self.toggle_numlock(),
+                (true, true) => self.handle_numlock_pressed(init_numlock),
+                (false, false) => (),
+            }
+        } else if init_numlock != 0 {
+            if self.config.disable_numlock_on_start() {
+                self.actions.push(Action::ToggleNumlock);
+            } else {
+                self.handle_numlock_pressed(init_numlock);
+            }
+        }
+        self.take_actions()
+    }
+
+    /// Bring the devices back in sync with our state after they reconnect.
+    ///
+    /// `numlock` is the state of the numlock LED, if a keyboard is connected.
+    pub(crate) fn restore_state(&mut self, numlock: Option<i32>) -> Vec<Action> {
+        if self.config.disable_numpad() {
+            return Vec::new();
+        }
+        // numlock might have been toggled while the keyboard was away
+        match numlock {
+            Some(numlock) => self.handle_numlock_pressed(numlock),
+            None => {
+                let brightness = if !self.state.numlock {
+                    Brightness::off()
+                } else if self.state.idle_dimmed {
+                    self.levels.dimmest()
+                } else {
+                    self.state.brightness.clone()
+                };
+                self.set_brightness(brightness);
+            }
+        }
+        self.take_actions()
+    }
+
+    /// Release any keys, and forget about the contacts and the grab.
+    pub(crate) fn on_touchpad_lost(&mut self) -> Vec<Action> {
+        // don't leave any keys pressed, but don't trigger an unfinished calc drag either
+        if self.state.cur_key == CurKey::Calc {
+            self.state.cur_key.reset();
+        }
+        self.on_lift();
+        // the grab goes away with the device
+        self.grab.grabbed = false;
+        self.grab.release_pending = false;
+        self.grab.linger.stop();
+        self.state.fingers_down = false;
+        self.take_actions()
+    }
+
+    /// Start over with new contacts, once the touchpad is back.
+    pub(crate) fn on_touchpad_reconnected(&mut self, touchpad: &TouchpadInfo) {
+        self.contacts = Self::new_contacts(&self.config, &self.gestures, touchpad);
+    }
+
+    fn grab(&mut self) {
+        self.grab.release_pending = false;
+        self.grab.linger.stop();
+        if self.grab.grabbed {
+            return;
+        }
+        debug!("Grabbing");
+        self.grab.grabbed = true;
+        self.actions.push(Action::Grab);
+    }
+
+    fn ungrab(&mut self) {
+        self.grab.release_pending = false;
+        self.grab.linger.stop();
+        if !self.grab.grabbed {
+            return;
+        }
+        debug!("Ungrabbing");
+        self.grab.grabbed = false;
+        self.actions.push(Action::Ungrab);
+    }
+
+    /// Release the grab as soon as it is safe, since the pointer is being used.
+    fn release_grab(&mut self) {
+        if !self.grab.grabbed {
+            return;
+        }
+        if self.state.fingers_down {
+            debug!("Will ungrab once all fingers lift");
+            self.grab.release_pending = true;
+        } else {
+            self.ungrab();
+        }
+    }
+
+    /// Called when all the fingers are lifted off the pad.
+    fn on_all_lifted(&mut self, time: TimeVal) {
+        if !self.grab.grabbed {
+            return;
+        }
+        if self.grab.release_pending {
+            self.ungrab();
+        } else {
+            self.grab.linger.reset(time);
+        }
+    }
+
+    fn key_events(&self, key: EV_KEY) -> Vec<EV_KEY> {
+        if self.layout.needs_multikey(key) {
+            self.layout.multikeys(key).to_vec()
+        } else {
+            vec![key]
+        }
+    }
+
+    fn on_lift(&mut self) {
+        // end of tap
+        debug!("End tap");
+        if self.state.cur_key == CurKey::Calc
+            && self.state.pos.dist_sq(self.state.tap_start_pos) >= self.gestures.calc_drag_dist_sq
+        {
+            self.toggle_calc();
+        }
+
+        if self.state.finger_state == FingerState::Touching {
+            if let CurKey::Numpad(key) = self.state.cur_key {
+                debug!("Keyup {:?}", key);
+                self.actions.push(Action::KeyUp(self.key_events(key)));
+                // stay grabbed, so that a quick second tap doesn't reach the pointer
+            }
+        }
+        self.state.cur_key.reset();
+        self.state.finger_state = FingerState::Lifted;
+    }
+
+    fn on_tap(&mut self, time: TimeVal) {
+        if self.state.finger_state == FingerState::Lifted {
+            // start of tap
+            debug!("Start tap");
+            self.state.finger_state = FingerState::TouchStart;
+            self.state.tap_started_at = time;
+            self.state.tap_start_pos = self.state.pos;
+            self.state.tapped_outside_numlock_bbox = false;
+            self.state.finger_dragged_too_much = false;
+            if self.state.numlock {
+                self.state.cur_key = match self.layout.get_key(self.state.pos) {
+                    Some(key) => {
+                        self.grab();
+                        self.state.finger_state = FingerState::Touching;
+
+                        debug!("Keydown {:?}", key);
+                        self.actions.push(Action::KeyDown(self.key_events(key)));
+                        CurKey::Numpad(key)
+                    }
+                    None => CurKey::None,
+                };
+                if self.state.cur_key != CurKey::None {
+                    self.on_numpad_used();
+                }
+            }
+        }
+        if self.layout.in_numlock_bbox(self.state.pos) && !self.config.disable_numpad() {
+            debug!("In numlock - start");
+            self.state.finger_state = FingerState::Touching;
+            self.state.cur_key = CurKey::Numlock;
+        } else {
+            if self.layout.in_calc_bbox(self.state.pos) {
+                debug!("In calc - start");
+                self.state.finger_state = FingerState::Touching;
+                self.state.cur_key = CurKey::Calc;
+            }
+            self.state.tapped_outside_numlock_bbox = true
+        }
+    }
+
+    /// Whether a touch at `time` is too soon after typing on the internal keyboard.
+    fn is_typing(&self, time: TimeVal) -> bool {
+        let (Some(window), Some(last_typed_at)) =
+            (self.config.disable_while_typing(), self.state.last_typed_at)
+        else {
+            return false;
+        };
+        // the keyboard might have been read before an older touchpad event
+        time <= last_typed_at || time.elapsed_since(last_typed_at) < window
+    }
+
+    /// Handle the changes to the contacts at the end of an evdev frame.
+    fn handle_frame(&mut self, time: TimeVal) {
+        match self.contacts.end_frame() {
+            Frame::Idle => (),
+            Frame::Touch(pos) => {
+                self.state.pos = pos;
+                if self.is_typing(time) {
+                    debug!("Ignoring touch while typing");
+                } else if !self.state.finger_dragged_too_much
+                    || time.elapsed_since(self.state.dragged_finger_lifted_at)
+                        >= self.gestures.numlock_hold
+                {
+                    self.on_tap(time);
+                }
+            }
+            Frame::Hold(pos) => self.state.pos = pos,
+            Frame::Lift => {
+                if !self.state.finger_dragged_too_much {
+                    // only call on_lift if we did not already call it as a result of finger drag
+                    self.on_lift();
+                } else {
+                    self.state.dragged_finger_lifted_at = time;
+                }
+            }
+            Frame::Cancel => {
+                debug!("Extra finger or palm - cancelling key");
+                if self.state.finger_state != FingerState::Lifted {
+                    // an unfinished calc drag must not trigger either
+                    if self.state.cur_key == CurKey::Calc {
+                        self.state.cur_key.reset();
+                    }
+                    self.state.finger_dragged_too_much = true;
+                    self.release_grab();
+                    self.on_lift();
+                }
+            }
+        }
+    }
+
+    pub(crate) fn handle_touchpad_event(&mut self, ev: &InputEvent) -> Vec<Action> {
+        // no need to trace timestamp events - too noisy
+        if !matches!(ev.event_code, EventCode::EV_MSC(EV_MSC::MSC_TIMESTAMP)) {
+            trace!("TP {:?} {}", ev.event_code, ev.value);
+        }
+        self.contacts.handle_event(&ev.event_code, ev.value);
+        match ev.event_code {
+            EventCode::EV_SYN(EV_SYN::SYN_REPORT) => {
+                let fingers_down = self.contacts.any_down();
+                let was_down = std::mem::replace(&mut self.state.fingers_down, fingers_down);
+                if fingers_down && !was_down {
+                    // a new touch only keeps the lingering grab if it presses a key
+                    self.grab.linger.stop();
+                    self.release_grab();
+                }
+                self.handle_frame(ev.time);
+                if was_down && !fingers_down {
+                    self.on_all_lifted(ev.time);
+                }
+            }
+            EventCode::EV_MSC(EV_MSC::MSC_TIMESTAMP) => {
+                // The toggle should happen automatically after the hold duration, even if user is
+                // still touching the numpad bbox.
+                if self.state.finger_state == FingerState::TouchStart {
+                    trace!("Touch {}", self.state.pos);
+                }
+
+                if self.state.finger_state == FingerState::Touching
+                    && !self.state.tapped_outside_numlock_bbox
+                    && !self.config.disable_numpad()
+                {
+                    if self.layout.in_numlock_bbox(self.state.pos) {
+                        if ev.time.elapsed_since(self.state.tap_started_at)
+                            >= self.gestures.numlock_hold
+                        {
+                            debug!("Hold finish - toggle numlock");
+                            self.toggle_numlock();
+                            // If user doesn't lift the finger quickly, we don't want to keep
+                            // toggling, so assume finger was moved.
+                            // Can't do finger_state = Lifted, since that would start another tap
+                            self.state.finger_state = FingerState::TouchStart;
+                        }
+                    } else {
+                        self.state.tapped_outside_numlock_bbox = true;
+                    }
+                }
+                if self.state.numlock
+                    && self.state.cur_key == CurKey::Calc
+                    && self.layout.in_calc_bbox(self.state.pos)
+                    && ev.time.elapsed_since(self.state.tap_started_at)
+                        >= self.gestures.brightness_hold
+                {
+                    debug!("Hold finish - cycle brightness");
+                    let brightness = self.levels.next(&self.state.brightness);
+                    self.change_brightness(brightness);
+                    self.state.cur_key.reset();
+                }
+            }
+            _ => (),
+        }
+
+        // if the finger drags too much, stop the tap
+        // TODO: Use the same logic for numlock bbox instead of `tapped_outside_numlock_bbox`
+        if self.state.numlock
+            && self.state.finger_state == FingerState::Touching
+            && self.state.cur_key != CurKey::Calc // we are fine if finger drags on calc box
+            && self.state.tap_start_pos.dist_sq(self.state.pos) > self.gestures.tap_jitter_dist_sq
+        {
+            debug!("Moved too much");
+            self.state.finger_dragged_too_much = true;
+            self.release_grab();
+            self.on_lift();
+        }
+        self.take_actions()
+    }
+
+    /// Handle an event from a keyboard. `internal` is whether it is built into the laptop.
+    pub(crate) fn handle_keyboard_event(&mut self, ev: &InputEvent, internal: bool) -> Vec<Action> {
+        // Note: We only listen to the LED event, and not the numlock event.
+        // While most environments keep them in sync, it is technically possible
+        // to change the led state without changing the numlock state.
+        //
+        // But there is no simple way for us to figure out the actual numlock
+        // state. We would need to bring in Xlib (and equivalent for wayland)
+        // and query it to get the numlock state.
+        //
+        // So, we only listen for LED changes, hoping that it reflects numlock state
+        //
+        // The LED changes on all keyboards at once, so only act on the first one.
+        match ev.event_code {
+            EventCode::EV_LED(EV_LED::LED_NUML) if (ev.value != 0) != self.state.numlock => {
+                self.handle_numlock_pressed(ev.value);
+            }
+            // presses and autorepeats, but not releases
+            EventCode::EV_KEY(key) if ev.value != 0 && internal && counts_as_typing(key) => {
+                self.state.last_typed_at = Some(ev.time);
+            }
+            _ => (),
+        }
+        trace!("KB {}, {}", ev.event_code, ev.value);
+        self.take_actions()
+    }
+
+    /// Handle a key that [counts as typing](counts_as_typing) on the laptop's keyboard,
+    /// without knowing which one it was.
+    pub(crate) fn handle_typing(&mut self, time: TimeVal) -> Vec<Action> {
+        self.state.last_typed_at = Some(time);
+        self.take_actions()
+    }
+
+    /// How long until the next timer runs out, if any is running.
+    pub(crate) fn next_timeout(&self) -> Option<CustomDuration> {
+        let now = self.clock.now();
+        [&self.idle_timer, &self.grab.linger]
+            .into_iter()
+            .filter_map(|timer| timer.remaining(now))
+            .chain(self.fade.remaining(now))
+            .min()
+    }
+
+    /// Handle any timers that have run out.
+    pub(crate) fn handle_timeouts(&mut self) -> Vec<Action> {
+        if self.idle_timer.check_expired(self.clock.now()) {
+            self.on_idle();
+        }
+        if self.grab.linger.check_expired(self.clock.now()) {
+            debug!("Numpad not touched for a while");
+            self.ungrab();
+        }
+        if let Some(brightness) = self.fade.next_step(self.clock.now()) {
+            self.actions.push(Action::SetBrightness(brightness));
+        }
+        self.take_actions()
+    }
+}
+
+/// Whether pressing `key` means that the user is typing, and the touchpad should be ignored.
+///
+/// Like libinput, modifiers are excluded so that eg. Ctrl+click still works. So are the
+/// keypad keys, since using a real numpad along with the touchpad one is fine.
+pub(crate) fn counts_as_typing(key: EV_KEY) -> bool {
+    !matches!(
+        key,
+        EV_KEY::KEY_LEFTCTRL
+            | EV_KEY::KEY_RIGHTCTRL
+            | EV_KEY::KEY_LEFTSHIFT
+            | EV_KEY::KEY_RIGHTSHIFT
+            | EV_KEY::KEY_LEFTALT
+            | EV_KEY::KEY_RIGHTALT
+            | EV_KEY::KEY_LEFTMETA
+            | EV_KEY::KEY_RIGHTMETA
+            | EV_KEY::KEY_FN
+            | EV_KEY::KEY_NUMLOCK
+            | EV_KEY::KEY_KP0
+            | EV_KEY::KEY_KP1
+            | EV_KEY::KEY_KP2
+            | EV_KEY::KEY_KP3
+            | EV_KEY::KEY_KP4
+            | EV_KEY::KEY_KP5
+            | EV_KEY::KEY_KP6
+            | EV_KEY::KEY_KP7
+            | EV_KEY::KEY_KP8
+            | EV_KEY::KEY_KP9
+            | EV_KEY::KEY_KPDOT
+            | EV_KEY::KEY_KPCOMMA
+            | EV_KEY::KEY_KPPLUS
+            | EV_KEY::KEY_KPMINUS
+            | EV_KEY::KEY_KPASTERISK
+            | EV_KEY::KEY_KPSLASH
+            | EV_KEY::KEY_KPENTER
+            | EV_KEY::KEY_KPEQUAL
+            | EV_KEY::KEY_KPLEFTPAREN
+            | EV_KEY::KEY_KPRIGHTPAREN
+            | EV_KEY::KEY_KPPLUSMINUS
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use evdev_rs::enums::EV_ABS;
+
+    use super::*;
+    use crate::backlight::{Backlight, RecordingBacklight};
+    use crate::brightness::default_level;
+    use crate::gestures::Resolution;
+    use crate::numpad_layout::{BBox, SupportedLayout};
+    use crate::util::FakeClock;
+
+    const TRACKING_ID: EventCode = EventCode::EV_ABS(EV_ABS::ABS_MT_TRACKING_ID);
+    const X: EventCode = EventCode::EV_ABS(EV_ABS::ABS_MT_POSITION_X);
+    const Y: EventCode = EventCode::EV_ABS(EV_ABS::ABS_MT_POSITION_Y);
+
+    // Positions on the UX433FA layout, scaled to a 1000x1000 touchpad
+    const NUMLOCK: (i32, i32) = (980, 40);
+    const CALC: (i32, i32) = (20, 40);
+    const KP7: (i32, i32) = (140, 200);
+
+    fn new_engine(config: &str) -> Engine<FakeClock> {
+        let touchpad = TouchpadInfo {
+            name: "Test touchpad".to_owned(),
+            detected: Some(SupportedLayout::UX433FA),
+            bbox: BBox::new(0, 1000, 0, 1000),
+            resolution: Resolution { x: 10, y: 10 },
+            palm_axes: Default::default(),
+            cur_slot: 0,
+            slots: Vec::new(),
+        };
+        let config = toml::from_str(config).unwrap();
+        Engine::from_config(config, &touchpad, FakeClock::new()).unwrap()
+    }
+
+    fn event(engine: &Engine<FakeClock>, code: EventCode, value: i32) -> InputEvent {
+        InputEvent::new(&engine.clock.now(), &code, value)
+    }
+
+    /// Send a frame with the given changes, like the touchpad does.
+    fn frame(engine: &mut Engine<FakeClock>, events: &[(EventCode, i32)]) -> Vec<Action> {
+        let timestamp = (EventCode::EV_MSC(EV_MSC::MSC_TIMESTAMP), 0);
+        let report = (EventCode::EV_SYN(EV_SYN::SYN_REPORT), 0);
+        let mut actions = Vec::new();
+        for &(code, value) in events.iter().chain([&timestamp, &report]) {
+            let ev = event(engine, code, value);
+            actions.extend(engine.handle_touchpad_event(&ev));
+        }
+        actions
+    }
+
+    fn touch(engine: &mut Engine<FakeClock>, (x, y): (i32, i32)) -> Vec<Action> {
+        frame(engine, &[(TRACKING_ID, 1), (X, x), (Y, y)])
+    }
+
+    fn move_to(engine: &mut Engine<FakeClock>, (x, y): (i32, i32)) -> Vec<Action> {
+        frame(engine, &[(X, x), (Y, y)])
+    }
+
+    fn lift(engine: &mut Engine<FakeClock>) -> Vec<Action> {
+        frame(engine, &[(TRACKING_ID, -1)])
+    }
+
+    /// Keep the finger still for `ms`. The touchpad keeps sending timestamps meanwhile.
+    fn hold(engine: &mut Engine<FakeClock>, ms: u64) -> Vec<Action> {
+        engine.clock.advance(CustomDuration::from_millis(ms));
+        frame(engine, &[])
+    }
+
+    fn keyboard(engine: &mut Engine<FakeClock>, code: EventCode, internal: bool) -> Vec<Action> {
+        let ev = event(engine, code, 1);
+        engine.handle_keyboard_event(&ev, internal)
+    }
+
+    fn numlock_on(engine: &mut Engine<FakeClock>) {
+        let actions = keyboard(engine, EventCode::EV_LED(EV_LED::LED_NUML), false);
+        assert_eq!(actions, [Action::SetBrightness(default_level("full"))]);
+    }
+
+    #[test]
+    fn test_numlock_hold() {
+        let mut engine = new_engine("");
+        // a short tap does nothing
+        assert_eq!(touch(&mut engine, NUMLOCK), []);
+        assert_eq!(hold(&mut engine, 100), []);
+        assert_eq!(lift(&mut engine), []);
+
+        assert_eq!(touch(&mut engine, NUMLOCK), []);
+        assert_eq!(hold(&mut engine, 100), []);
+        assert_eq!(
+            hold(&mut engine, 150),
+            [
+                Action::SetBrightness(default_level("full")),
+                Action::ToggleNumlock
+            ]
+        );
+        // keeping the finger down doesn't toggle it again
+        assert_eq!(hold(&mut engine, 300), []);
+        assert_eq!(lift(&mut engine), []);
+
+        assert_eq!(touch(&mut engine, NUMLOCK), []);
+        assert_eq!(
+            hold(&mut engine, 250),
+            [
+                Action::SetBrightness(Brightness::off()),
+                Action::ToggleNumlock
+            ]
+        );
+        assert_eq!(lift(&mut engine), []);
+    }
+
+    #[test]
+    fn test_tap_key() {
+        let mut engine = new_engine("");
+        // numpad is off
+        assert_eq!(touch(&mut engine, KP7), []);
+        assert_eq!(lift(&mut engine), []);
+
+        numlock_on(&mut engine);
+        assert_eq!(
+            touch(&mut engine, KP7),
+            [Action::Grab, Action::KeyDown(vec![EV_KEY::KEY_KP7])]
+        );
+        // holding the key is left to the autorepeat
+        assert_eq!(hold(&mut engine, 1000), []);
+        assert_eq!(lift(&mut engine), [Action::KeyUp(vec![EV_KEY::KEY_KP7])]);
+
+        // the grab lingers for a while after the last tap
+        assert_eq!(
+            engine.next_timeout(),
+            Some(CustomDuration::from_millis(500))
+        );
+        engine.clock.advance(CustomDuration::from_millis(500));
+        assert_eq!(engine.handle_timeouts(), [Action::Ungrab]);
+        assert_eq!(engine.next_timeout(), None);
+    }
+
+    #[test]
+    fn test_drag_cancels_key() {
+        let mut engine = new_engine("");
+        numlock_on(&mut engine);
+        assert_eq!(
+            touch(&mut engine, KP7),
+            [Action::Grab, Action::KeyDown(vec![EV_KEY::KEY_KP7])]
+        );
+        assert_eq!(
+            move_to(&mut engine, (180, 200)),
+            [],
+            "small jitter is allowed"
+        );
+        assert_eq!(
+            move_to(&mut engine, (300, 200)),
+            [Action::KeyUp(vec![EV_KEY::KEY_KP7])]
+        );
+        // dragging over the other keys doesn't press them
+        assert_eq!(move_to(&mut engine, (500, 500)), []);
+        // the grab is only released once the finger is lifted
+        assert_eq!(lift(&mut engine), [Action::Ungrab]);
+        assert_eq!(engine.next_timeout(), None);
+    }
+
+    #[test]
+    fn test_pointer_stroke_while_lingering() {
+        let mut engine = new_engine("");
+        numlock_on(&mut engine);
+        touch(&mut engine, KP7);
+        lift(&mut engine);
+        // the pointer is used before the grab runs out
+        engine.clock.advance(CustomDuration::from_millis(200));
+        assert_eq!(touch(&mut engine, (500, 40)), []);
+        assert_eq!(move_to(&mut engine, (600, 60)), []);
+        assert_eq!(engine.next_timeout(), None);
+        assert_eq!(lift(&mut engine), [Action::Ungrab]);
+        assert_eq!(engine.next_timeout(), None);
+    }
+
+    #[test]
+    fn test_drag_off_numpad_while_lingering() {
+        let mut engine = new_engine("");
+        numlock_on(&mut engine);
+        touch(&mut engine, KP7);
+        lift(&mut engine);
+        engine.clock.advance(CustomDuration::from_millis(200));
+        assert_eq!(
+            touch(&mut engine, KP7),
+            [Action::KeyDown(vec![EV_KEY::KEY_KP7])]
+        );
+        assert_eq!(
+            move_to(&mut engine, (500, 40)),
+            [Action::KeyUp(vec![EV_KEY::KEY_KP7])]
+        );
+        assert_eq!(lift(&mut engine), [Action::Ungrab]);
+        assert_eq!(engine.next_timeout(), None);
+    }
+
+    #[test]
+    fn test_calc_toggle() {
+        let mut engine = new_engine("");
+        // too short of a drag
+        assert_eq!(touch(&mut engine, CALC), []);
+        assert_eq!(move_to(&mut engine, (200, 40)), []);
+        assert_eq!(lift(&mut engine), []);
+
+        assert_eq!(touch(&mut engine, CALC), []);
+        assert_eq!(move_to(&mut engine, (400, 40)), []);
+        assert_eq!(lift(&mut engine), [Action::StartCalc]);
+
+        assert_eq!(touch(&mut engine, CALC), []);
+        assert_eq!(move_to(&mut engine, (200, 300)), []);
+        assert_eq!(lift(&mut engine), [Action::StopCalc]);
+    }
+
+    #[test]
+    fn test_brightness_cycle() {
+        let mut engine = new_engine("");
+        // can't change the brightness while the numpad is off
+        assert_eq!(touch(&mut engine, CALC), []);
+        assert_eq!(hold(&mut engine, 300), []);
+        assert_eq!(lift(&mut engine), []);
+
+        numlock_on(&mut engine);
+        for brightness in [
+            default_level("low"),
+            default_level("half"),
+            default_level("full"),
+        ] {
+            assert_eq!(touch(&mut engine, CALC), []);
+            assert_eq!(hold(&mut engine, 300), [Action::SetBrightness(brightness)]);
+            assert_eq!(hold(&mut engine, 300), []);
+            assert_eq!(lift(&mut engine), []);
+        }
+    }
+
+    #[test]
+    fn test_backlight_sequence() {
+        let mut engine = new_engine("idle_timeout = 10\nidle_action = \"dim\"");
+        let recording = RecordingBacklight::default();
+        let mut backlight: Box<dyn Backlight> = Box::new(recording.clone());
+        let mut apply = |actions: Vec<Action>| {
+            for action in actions {
+                if let Action::SetBrightness(brightness) = action {
+                    backlight.set_brightness(&brightness).unwrap();
+                }
+            }
+        };
+        // turn on with the numlock button, cycle the brightness twice with the calc button
+        for (pos, times) in [(NUMLOCK, 1), (CALC, 2)] {
+            for _ in 0..times {
+                apply(touch(&mut engine, pos));
+                apply(hold(&mut engine, 300));
+                apply(lift(&mut engine));
+            }
+        }
+        engine.clock.advance(CustomDuration::from_secs(10));
+        apply(engine.handle_timeouts());
+        // lights up again once touched
+        apply(touch(&mut engine, KP7));
+        apply(lift(&mut engine));
+        apply(touch(&mut engine, NUMLOCK));
+        apply(hold(&mut engine, 300));
+        apply(lift(&mut engine));
+        let history: Vec<_> = recording
+            .history()
+            .iter()
+            .map(Brightness::to_string)
+            .collect();
+        assert_eq!(history, ["full", "low", "half", "low", "half", "off"]);
+    }
+
+    #[test]
+    fn test_idle() {
+        let mut engine = new_engine("idle_timeout = 10");
+        numlock_on(&mut engine);
+        assert_eq!(engine.next_timeout(), Some(CustomDuration::from_secs(10)));
+        engine.clock.advance(CustomDuration::from_secs(10));
+        assert_eq!(
+            engine.handle_timeouts(),
+            [
+                Action::SetBrightness(Brightness::off()),
+                Action::ToggleNumlock
+            ]
+        );
+
+        let mut engine = new_engine("idle_timeout = 10\nidle_action = \"dim\"");
+        numlock_on(&mut engine);
+        engine.clock.advance(CustomDuration::from_secs(10));
+        assert_eq!(
+            engine.handle_timeouts(),
+            [Action::SetBrightness(default_level("low"))]
+        );
+        // the numpad is still usable, and lights up again
+        assert_eq!(
+            touch(&mut engine, KP7),
+            [
+                Action::Grab,
+                Action::KeyDown(vec![EV_KEY::KEY_KP7]),
+                Action::SetBrightness(default_level("full"))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_control() {
+        let mut engine = new_engine("");
+        assert_eq!(
+            engine.set_numlock(true),
+            [
+                Action::SetBrightness(default_level("full")),
+                Action::ToggleNumlock
+            ]
+        );
+        assert_eq!(engine.set_numlock(true), []);
+        assert_eq!(
+            engine.set_brightness_level("half").unwrap(),
+            [Action::SetBrightness(default_level("half"))]
+        );
+        assert_eq!(
+            engine.cycle_brightness(),
+            [Action::SetBrightness(default_level("full"))]
+        );
+        assert_eq!(
+            engine.status(),
+            Status {
+                numlock: true,
+                brightness: default_level("full"),
+                calc_open: false,
+            }
+        );
+        assert_eq!(
+            engine.set_numlock(false),
+            [
+                Action::SetBrightness(Brightness::off()),
+                Action::ToggleNumlock
+            ]
+        );
+        assert_eq!(engine.trigger_calc(), [Action::StartCalc]);
+        assert_eq!(engine.trigger_calc(), [Action::StopCalc]);
+        // remembered for the next time the numpad is turned on
+        assert_eq!(engine.set_brightness_level("low").unwrap(), []);
+        assert_eq!(
+            engine.set_numlock(true),
+            [
+                Action::SetBrightness(default_level("low")),
+                Action::ToggleNumlock
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reload() {
+        let mut engine = new_engine("");
+        numlock_on(&mut engine);
+        assert_eq!(
+            touch(&mut engine, KP7),
+            [Action::Grab, Action::KeyDown(vec![EV_KEY::KEY_KP7])]
+        );
+        let touchpad = TouchpadInfo {
+            name: "Test touchpad".to_owned(),
+            detected: None,
+            bbox: BBox::new(0, 1000, 0, 1000),
+            resolution: Resolution { x: 10, y: 10 },
+            palm_axes: Default::default(),
+            cur_slot: 0,
+            slots: Vec::new(),
+        };
+        // can't use the detected layout anymore
+        let config = toml::from_str("").unwrap();
+        assert!(engine.reload(config, &touchpad).is_err());
+        assert_eq!(engine.next_timeout(), None);
+        // nor an idle timeout that is out of range
+        for idle_timeout in [0, i64::MAX] {
+            let config = format!("layout = \"UX581\"\nidle_timeout = {}", idle_timeout);
+            let err = engine
+                .reload(toml::from_str(&config).unwrap(), &touchpad)
+                .unwrap_err();
+            assert!(err.to_string().starts_with("idle_timeout must be between"));
+        }
+
+        let config = toml::from_str("layout = \"UX581\"\nidle_timeout = 10").unwrap();
+        assert_eq!(
+            engine.reload(config, &touchpad).unwrap(),
+            [Action::KeyUp(vec![EV_KEY::KEY_KP7]), Action::Ungrab]
+        );
+        assert!(engine.status().numlock);
+        assert_eq!(engine.next_timeout(), Some(CustomDuration::from_secs(10)));
+        // the top row of the UX581 has the `=` key
+        assert_eq!(lift(&mut engine), []);
+        assert_eq!(
+            touch(&mut engine, KP7),
+            [Action::Grab, Action::KeyDown(vec![EV_KEY::KEY_KPEQUAL])]
+        );
+    }
+
+    #[test]
+    fn test_saved_state() {
+        let saved = |brightness: &str, numlock| SavedState {
+            brightness: brightness.to_owned(),
+            numlock,
+        };
+        // numlock is still turned off at startup
+        let mut engine = new_engine("");
+        assert_eq!(
+            engine.initialize_numlock(1, Some(&saved("low", true))),
+            [Action::ToggleNumlock]
+        );
+        assert_eq!(engine.saved_state(), saved("low", false));
+        assert_eq!(
+            engine.set_numlock(true),
+            [
+                Action::SetBrightness(default_level("low")),
+                Action::ToggleNumlock
+            ]
+        );
+        engine.cycle_brightness();
+        assert_eq!(engine.saved_state(), saved("half", true));
+
+        let mut engine = new_engine("restore_numlock = true");
+        assert_eq!(
+            engine.initialize_numlock(0, Some(&saved("half", true))),
+            [
+                Action::SetBrightness(default_level("half")),
+                Action::ToggleNumlock
+            ]
+        );
+        let mut engine = new_engine("restore_numlock = true");
+        assert_eq!(
+            engine.initialize_numlock(1, Some(&saved("half", false))),
+            [Action::ToggleNumlock]
+        );
+        let mut engine = new_engine("restore_numlock = true");
+        assert_eq!(engine.initialize_numlock(0, None), []);
+
+        // the level might be gone from the config
+        let mut engine = new_engine("disable_numlock_on_start = false");
+        assert_eq!(
+            engine.initialize_numlock(1, Some(&saved("blinding", false))),
+            [Action::SetBrightness(default_level("full"))]
+        );
+    }
+
+    #[test]
+    fn test_fade() {
+        let mut engine = new_engine("[brightness]\nfade_ms = 300");
+        let brightness = |actions: Vec<Action>| -> Vec<u8> {
+            actions
+                .into_iter()
+                .filter_map(|action| match action {
+                    Action::SetBrightness(brightness) => Some(brightness.raw()),
+                    _ => None,
+                })
+                .collect()
+        };
+        // fades from off, one raw value per step
+        assert_eq!(brightness(engine.set_numlock(true)), [31]);
+        engine.clock.advance(CustomDuration::from_millis(100));
+        assert_eq!(brightness(engine.handle_timeouts()), [21]);
+        assert_eq!(
+            engine.next_timeout().unwrap().as_millis_ceil(),
+            10,
+            "the next step is due"
+        );
+        // the numpad can be used meanwhile
+        assert_eq!(
+            touch(&mut engine, KP7),
+            [Action::Grab, Action::KeyDown(vec![EV_KEY::KEY_KP7])]
+        );
+        engine.clock.advance(CustomDuration::from_millis(200));
+        assert_eq!(
+            engine.handle_timeouts(),
+            [Action::SetBrightness(default_level("full"))]
+        );
+        assert_eq!(lift(&mut engine), [Action::KeyUp(vec![EV_KEY::KEY_KP7])]);
+
+        // turning it off midway fades back from where it got to
+        engine.set_numlock(false);
+        engine.clock.advance(CustomDuration::from_millis(150));
+        assert_eq!(brightness(engine.handle_timeouts()), [17]);
+        assert_eq!(brightness(engine.set_numlock(true)), [16]);
+        assert_eq!(engine.status().brightness, default_level("full"));
+    }
+
+    #[test]
+    fn test_disable_while_typing() {
+        let mut engine = new_engine("disable_while_typing_ms = 300");
+        numlock_on(&mut engine);
+        // an external keyboard doesn't count
+        keyboard(&mut engine, EventCode::EV_KEY(EV_KEY::KEY_A), false);
+        assert_eq!(
+            touch(&mut engine, KP7),
+            [Action::Grab, Action::KeyDown(vec![EV_KEY::KEY_KP7])]
+        );
+        assert_eq!(lift(&mut engine), [Action::KeyUp(vec![EV_KEY::KEY_KP7])]);
+
+        keyboard(&mut engine, EventCode::EV_KEY(EV_KEY::KEY_A), true);
+        assert_eq!(touch(&mut engine, KP7), []);
+        // the ignored touch doesn't keep the touchpad grabbed
+        assert_eq!(lift(&mut engine), [Action::Ungrab]);
+        hold(&mut engine, 300);
+        assert_eq!(
+            touch(&mut engine, KP7),
+            [Action::Grab, Action::KeyDown(vec![EV_KEY::KEY_KP7])]
+        );
+    }
+
+    #[test]
+    fn test_counts_as_typing() {
+        for key in [
+            EV_KEY::KEY_A,
+            EV_KEY::KEY_1,
+            EV_KEY::KEY_SPACE,
+            EV_KEY::KEY_BACKSPACE,
+        ] {
+            assert!(counts_as_typing(key), "{:?}", key);
+        }
+        for key in [
+            EV_KEY::KEY_LEFTCTRL,
+            EV_KEY::KEY_RIGHTSHIFT,
+            EV_KEY::KEY_LEFTMETA,
+            EV_KEY::KEY_NUMLOCK,
+            EV_KEY::KEY_KP7,
+            EV_KEY::KEY_KPENTER,
+        ] {
+            assert!(!counts_as_typing(key), "{:?}", key);
+        }
+    }
+}
@@ -0,0 +1,191 @@
+use evdev_rs::TimeVal;
+
+use crate::brightness::Brightness;
+use crate::util::{CustomDuration, ElapsedSince};
+
+/// How bright a raw value is, from 0 (off) to 31 (brightest).
+///
+/// The levels are checked to be within the firmware's range, 1 to 31.
+fn intensity(raw: u8) -> u8 {
+    if raw == 0 { 0 } else { 32 - raw }
+}
+
+fn raw(intensity: u8) -> u8 {
+    if intensity == 0 { 0 } else { 32 - intensity }
+}
+
+#[derive(Debug)]
+struct Running {
+    target: Brightness,
+    /// Intensity that the fade started from
+    from: u8,
+    /// Number of steps until the target, one per intensity
+    steps: u8,
+    /// Steps already taken
+    done: u8,
+    started_at: TimeVal,
+    duration: CustomDuration,
+}
+
+impl Running {
+    /// When the given step is due, after the start. The first one is due right away.
+    fn step_time(&self, step: u8) -> CustomDuration {
+        if self.steps == 1 {
+            return CustomDuration::from_millis(0);
+        }
+        self.duration
+            .mul_div(step as u64 - 1, self.steps as u64 - 1)
+    }
+
+    fn step(&self, step: u8) -> Brightness {
+        if step == self.steps {
+            return self.target.clone();
+        }
+        let to = intensity(self.target.raw());
+        let intensity = if to > self.from {
+            self.from + step
+        } else {
+            self.from - step
+        };
+        Brightness::fading_to(&self.target, raw(intensity))
+    }
+}
+
+/// Steps the backlight through the values between two levels, so that it fades.
+#[derive(Debug)]
+pub(crate) struct Fade {
+    /// `None` if fading is disabled
+    duration: Option<CustomDuration>,
+    /// `None` while not fading
+    running: Option<Running>,
+    /// The raw value last sent to the backlight
+    shown: u8,
+}
+
+impl Fade {
+    pub(crate) fn new(duration: Option<CustomDuration>) -> Self {
+        Self {
+            duration,
+            running: None,
+            // the backlight is off until the numpad is turned on
+            shown: 0,
+        }
+    }
+
+    /// Use a new duration for the next fades. A fade in progress keeps going.
+    pub(crate) fn set_duration(&mut self, duration: Option<CustomDuration>) {
+        self.duration = duration;
+    }
+
+    /// Start fading to `target` from what is shown now, and return the first step.
+    ///
+    /// If fading is disabled, that is the target itself.
+    pub(crate) fn start(&mut self, target: Brightness, now: TimeVal) -> Brightness {
+        self.running = None;
+        let Some(duration) = self.duration else {
+            self.shown = target.raw();
+            return target;
+        };
+        let (from, to) = (intensity(self.shown), intensity(target.raw()));
+        let running = Running {
+            target,
+            from,
+            steps: from.abs_diff(to).max(1),
+            done: 0,
+            started_at: now,
+            duration,
+        };
+        self.running = Some(running);
+        self.next_step(now).unwrap()
+    }
+
+    /// Time left until the next step, or `None` if not fading.
+    pub(crate) fn remaining(&self, now: TimeVal) -> Option<CustomDuration> {
+        let running = self.running.as_ref()?;
+        let next = running.step_time(running.done + 1);
+        Some(next.saturating_sub(now.elapsed_since(running.started_at)))
+    }
+
+    /// The latest step that is due, if any. Steps that were missed are skipped.
+    pub(crate) fn next_step(&mut self, now: TimeVal) -> Option<Brightness> {
+        let running = self.running.as_mut()?;
+        let elapsed = now.elapsed_since(running.started_at);
+        let due = (running.done + 1..=running.steps)
+            .take_while(|&step| running.step_time(step) <= elapsed)
+            .last()?;
+        running.done = due;
+        let brightness = running.step(due);
+        self.shown = brightness.raw();
+        if due == running.steps {
+            self.running = None;
+        }
+        Some(brightness)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brightness::default_level;
+    use crate::util::{Clock, FakeClock};
+
+    /// Run the fade to the end, one timeout at a time, and return the values it went through.
+    fn run(fade: &mut Fade, clock: &FakeClock, first: Brightness) -> Vec<u8> {
+        let mut values = vec![first.raw()];
+        while let Some(remaining) = fade.remaining(clock.now()) {
+            clock.advance(remaining);
+            values.extend(fade.next_step(clock.now()).map(|step| step.raw()));
+        }
+        values
+    }
+
+    #[test]
+    fn test_disabled() {
+        let clock = FakeClock::new();
+        let mut fade = Fade::new(None);
+        let full = default_level("full");
+        assert_eq!(fade.start(full.clone(), clock.now()), full);
+        assert_eq!(fade.remaining(clock.now()), None);
+        assert_eq!(fade.next_step(clock.now()), None);
+    }
+
+    #[test]
+    fn test_fade_in_and_out() {
+        let clock = FakeClock::new();
+        let mut fade = Fade::new(Some(CustomDuration::from_millis(300)));
+        let first = fade.start(default_level("half"), clock.now());
+        // 0 is off, then 31 is the dimmest
+        assert_eq!(first.raw(), 31);
+        assert_eq!(fade.remaining(clock.now()).unwrap().as_millis_ceil(), 43);
+        let values = run(&mut fade, &clock, first);
+        assert_eq!(values, [31, 30, 29, 28, 27, 26, 25, 24]);
+
+        let start = clock.now();
+        let first = fade.start(Brightness::off(), clock.now());
+        let values = run(&mut fade, &clock, first);
+        assert_eq!(values, [25, 26, 27, 28, 29, 30, 31, 0]);
+        assert_eq!(
+            clock.now().elapsed_since(start),
+            CustomDuration::from_millis(300)
+        );
+        assert_eq!(fade.remaining(clock.now()), None);
+    }
+
+    #[test]
+    fn test_interrupted() {
+        let clock = FakeClock::new();
+        let mut fade = Fade::new(Some(CustomDuration::from_millis(300)));
+        assert_eq!(fade.start(default_level("full"), clock.now()).raw(), 31);
+        // the poll loop was busy, so some steps are skipped
+        clock.advance(CustomDuration::from_millis(100));
+        assert_eq!(fade.next_step(clock.now()).unwrap().raw(), 21);
+        assert_eq!(fade.next_step(clock.now()), None);
+
+        // goes back from where it got to
+        let first = fade.start(default_level("low"), clock.now());
+        assert_eq!(first.raw(), 22);
+        let values = run(&mut fade, &clock, first);
+        assert_eq!(values.last(), Some(&31));
+        assert_eq!(values.len(), 10);
+    }
+}
@@ -0,0 +1,185 @@
+use anyhow::{Context, Result, bail, ensure};
+use serde::{Deserialize, Serialize};
+
+use crate::contacts::ExtraFingers;
+use crate::numpad_layout::BBox;
+use crate::util::CustomDuration;
+
+/// A distance on the touchpad surface.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub(crate) enum Distance {
+    /// Raw device units, as reported by evdev
+    Units(u32),
+    /// Millimetres, converted using the resolution reported by the touchpad
+    Mm(f32),
+    /// Fraction of the touchpad's diagonal
+    Diagonal(f32),
+}
+
+/// Resolution of the touchpad, in units per millimetre, as reported by `abs_info`.
+///
+/// Some touchpads report 0, in which case millimetres can't be used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Resolution {
+    pub(crate) x: i32,
+    pub(crate) y: i32,
+}
+
+impl Distance {
+    /// Convert to device units.
+    fn to_units(self, bbox: &BBox, resolution: Resolution) -> Result<f32> {
+        if let Self::Mm(value) | Self::Diagonal(value) = self {
+            ensure!(
+                value.is_finite() && value >= 0.0,
+                "Distances must be finite and not negative, got {:?}",
+                self
+            );
+        }
+        Ok(match self {
+            Self::Units(units) => units as f32,
+            Self::Mm(mm) => {
+                if resolution.x <= 0 || resolution.y <= 0 {
+                    bail!(
+                        "The touchpad doesn't report its resolution, \
+                        so distances can't be given in mm"
+                    );
+                }
+                // the resolutions are usually equal, but not always
+                mm * (resolution.x + resolution.y) as f32 / 2.0
+            }
+            Self::Diagonal(fraction) => fraction * bbox.diagonal(),
+        })
+    }
+}
+
+/// Gesture thresholds, as given in the config file.
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct GestureConfig {
+    /// How long the numlock button must be held to toggle numlock, in ms
+    numlock_hold_ms: u64,
+    /// How long the calc button must be held to cycle the brightness, in ms
+    brightness_hold_ms: u64,
+    /// How far a finger may move before a tap turns into a drag
+    tap_jitter: Distance,
+    /// How far the calc button must be dragged to trigger the calc command
+    calc_drag: Distance,
+    /// What to do when more fingers touch down while a key is pressed
+    extra_fingers: ExtraFingers,
+    /// How long the touchpad stays grabbed after the last numpad touch, in ms
+    grab_linger_ms: u64,
+}
+
+impl Default for GestureConfig {
+    fn default() -> Self {
+        Self {
+            numlock_hold_ms: 250,
+            brightness_hold_ms: 250,
+            tap_jitter: Distance::Units(100),
+            calc_drag: Distance::Units(300),
+            extra_fingers: Default::default(),
+            grab_linger_ms: 500,
+        }
+    }
+}
+
+/// Gesture thresholds, resolved for a specific touchpad.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Gestures {
+    pub(crate) numlock_hold: CustomDuration,
+    pub(crate) brightness_hold: CustomDuration,
+    /// Min Euclidean distance (squared) that a finger needs to move for a tap
+    /// to be changed into a drag.
+    pub(crate) tap_jitter_dist_sq: i32,
+    /// Min Euclidean distance (squared) that a finger needs to be dragged to
+    /// trigger the calculator key when numlock isn't active.
+    pub(crate) calc_drag_dist_sq: i32,
+    pub(crate) extra_fingers: ExtraFingers,
+    pub(crate) grab_linger: CustomDuration,
+}
+
+impl Gestures {
+    pub(crate) fn resolve(
+        config: &GestureConfig,
+        bbox: &BBox,
+        resolution: Resolution,
+    ) -> Result<Self> {
+        let dist_sq = |dist: Distance, name: &str| -> Result<i32> {
+            let units = dist.to_units(bbox, resolution).context(name.to_owned())?;
+            Ok(units.powi(2).round() as i32)
+        };
+        Ok(Self {
+            numlock_hold: CustomDuration::from_millis(config.numlock_hold_ms),
+            brightness_hold: CustomDuration::from_millis(config.brightness_hold_ms),
+            tap_jitter_dist_sq: dist_sq(config.tap_jitter, "tap_jitter")?,
+            calc_drag_dist_sq: dist_sq(config.calc_drag, "calc_drag")?,
+            extra_fingers: config.extra_fingers,
+            grab_linger: CustomDuration::from_millis(config.grab_linger_ms),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RESOLUTION: Resolution = Resolution { x: 31, y: 31 };
+
+    fn bbox() -> BBox {
+        // 3000x4000 units, so the diagonal is 5000
+        BBox::new(0, 3000, 0, 4000)
+    }
+
+    #[test]
+    fn test_defaults() {
+        let gestures = Gestures::resolve(&GestureConfig::default(), &bbox(), RESOLUTION).unwrap();
+        assert_eq!(
+            gestures,
+            Gestures {
+                numlock_hold: CustomDuration::from_millis(250),
+                brightness_hold: CustomDuration::from_millis(250),
+                tap_jitter_dist_sq: 10000,
+                calc_drag_dist_sq: 90000,
+                extra_fingers: ExtraFingers::Pointer,
+                grab_linger: CustomDuration::from_millis(500),
+            }
+        );
+    }
+
+    #[test]
+    fn test_units() {
+        let config: GestureConfig = toml::from_str(
+            r#"
+            brightness_hold_ms = 600
+            tap_jitter = { mm = 2 }
+            calc_drag = { diagonal = 0.1 }
+            "#,
+        )
+        .unwrap();
+        let gestures = Gestures::resolve(&config, &bbox(), RESOLUTION).unwrap();
+        assert_eq!(gestures.numlock_hold, CustomDuration::from_millis(250));
+        assert_eq!(gestures.brightness_hold, CustomDuration::from_millis(600));
+        assert_eq!(gestures.tap_jitter_dist_sq, 62 * 62);
+        assert_eq!(gestures.calc_drag_dist_sq, 500 * 500);
+
+        let no_resolution = Resolution { x: 0, y: 0 };
+        assert!(Gestures::resolve(&config, &bbox(), no_resolution).is_err());
+    }
+
+    #[test]
+    fn test_invalid_distances() {
+        let resolve = |gestures: &str| {
+            let config: GestureConfig = toml::from_str(gestures).unwrap();
+            Gestures::resolve(&config, &bbox(), RESOLUTION).map_err(|err| format!("{:#}", err))
+        };
+        assert_eq!(
+            resolve("tap_jitter = { mm = -2 }").unwrap_err(),
+            "tap_jitter: Distances must be finite and not negative, got Mm(-2.0)"
+        );
+        assert!(resolve("calc_drag = { diagonal = -0.1 }").is_err());
+        assert!(resolve("calc_drag = { diagonal = nan }").is_err());
+        assert!(resolve("tap_jitter = { mm = inf }").is_err());
+        assert!(resolve("tap_jitter = { mm = 0 }").is_ok());
+    }
+}
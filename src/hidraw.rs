@@ -0,0 +1,190 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind::PermissionDenied};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Error, Result, anyhow};
+use evdev_rs::{Device, DeviceWrapper};
+use log::debug;
+
+use crate::backlight::{Backlight, brightness_report};
+use crate::brightness::Brightness;
+
+/// Identifies a HID device, like the `HID_ID` line of its uevent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct HidId {
+    pub(crate) bus: u16,
+    pub(crate) vendor: u16,
+    pub(crate) product: u16,
+}
+
+impl HidId {
+    /// The ID of the HID device behind an evdev, which shares it.
+    pub(crate) fn of(evdev: &Device) -> Self {
+        Self {
+            bus: evdev.bustype(),
+            vendor: evdev.vendor_id(),
+            product: evdev.product_id(),
+        }
+    }
+
+    /// Parse the `HID_ID` of a uevent file, of the form `HID_ID=0018:00000B05:00004F00`.
+    fn from_uevent(uevent: &str) -> Option<Self> {
+        let id = uevent
+            .lines()
+            .find_map(|line| line.strip_prefix("HID_ID="))?;
+        let mut parts = id.split(':').map(|part| u32::from_str_radix(part, 16));
+        let mut next = || parts.next()?.ok().and_then(|part| part.try_into().ok());
+        Some(Self {
+            bus: next()?,
+            vendor: next()?,
+            product: next()?,
+        })
+    }
+}
+
+/// Find the `/dev/hidrawN` node of the HID device with the given ID.
+pub(crate) fn find_hidraw(sysfs_root: &Path, id: HidId) -> Result<PathBuf> {
+    let dir = sysfs_root.join("class/hidraw");
+    let entries =
+        std::fs::read_dir(&dir).with_context(|| format!("Could not read {}", dir.display()))?;
+    let mut names: Vec<_> = entries.flatten().map(|entry| entry.file_name()).collect();
+    // be deterministic if there are several, eg. for the different interfaces of a device
+    names.sort();
+    names
+        .into_iter()
+        .find(|name| {
+            std::fs::read_to_string(dir.join(name).join("device/uevent"))
+                .is_ok_and(|uevent| HidId::from_uevent(&uevent) == Some(id))
+        })
+        .map(|name| Path::new("/dev").join(name))
+        .ok_or_else(|| {
+            anyhow!(
+                "No hidraw device with ID {:04X}:{:04X}",
+                id.vendor,
+                id.product
+            )
+        })
+}
+
+/// Takes HID feature reports, like a hidraw node does.
+pub(crate) trait FeatureReports {
+    fn send_feature_report(&mut self, report: &[u8]) -> io::Result<()>;
+}
+
+impl FeatureReports for File {
+    fn send_feature_report(&mut self, report: &[u8]) -> io::Result<()> {
+        // HIDIOCSFEATURE(len), from linux/hidraw.h
+        let request = (3 << 30) | ((report.len() as u32) << 16) | ((b'H' as u32) << 8) | 0x06;
+        // Safety: the kernel only reads `report.len()` bytes from the buffer
+        let res = unsafe { libc::ioctl(self.as_raw_fd(), request as _, report.as_ptr()) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// Sets the brightness through the touchpad's hidraw node, going through the kernel's
+/// HID driver instead of talking to the I2C bus directly.
+pub(crate) struct HidrawBacklight<N = File> {
+    node: N,
+    path: PathBuf,
+}
+
+impl HidrawBacklight {
+    pub(crate) fn open(path: &Path) -> Result<Self> {
+        let node = OpenOptions::new().write(true).open(path).map_err(|err| {
+            let mut context = format!("Unable to open {}", path.display());
+            if err.kind() == PermissionDenied {
+                context.push_str(". Do you have the permission to write to /dev/hidraw*?");
+            }
+            Error::new(err).context(context)
+        })?;
+        debug!("Using {} for the backlight", path.display());
+        Ok(Self {
+            node,
+            path: path.to_owned(),
+        })
+    }
+}
+
+impl<N> std::fmt::Debug for HidrawBacklight<N> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "HidrawBacklight: {}", self.path.display())
+    }
+}
+
+impl<N: FeatureReports> Backlight for HidrawBacklight<N> {
+    fn set_brightness(&mut self, brightness: &Brightness) -> Result<()> {
+        self.node
+            .send_feature_report(&brightness_report(brightness))
+            .with_context(|| format!("Could not set touchpad brightness to {}", brightness))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use crate::brightness::default_level;
+
+    /// Writes the reports to a file, so that the test can read them back.
+    struct FakeNode(File);
+
+    impl FeatureReports for FakeNode {
+        fn send_feature_report(&mut self, report: &[u8]) -> io::Result<()> {
+            self.0.write_all(report)
+        }
+    }
+
+    const TOUCHPAD: HidId = HidId {
+        bus: 0x18,
+        vendor: 0x0b05,
+        product: 0x4f00,
+    };
+
+    #[test]
+    fn test_find_hidraw() {
+        let sysfs = tempfile::tempdir().unwrap();
+        let add = |name: &str, id: &str| {
+            let dir = sysfs.path().join("class/hidraw").join(name).join("device");
+            std::fs::create_dir_all(&dir).unwrap();
+            let uevent = format!("DRIVER=hid-multitouch\nHID_ID={}\nHID_NAME=Test\n", id);
+            std::fs::write(dir.join("uevent"), uevent).unwrap();
+        };
+        add("hidraw0", "0003:0000046D:0000C52B");
+        add("hidraw1", "0018:00000B05:00004F00");
+
+        assert_eq!(
+            find_hidraw(sysfs.path(), TOUCHPAD).unwrap(),
+            Path::new("/dev/hidraw1")
+        );
+        let other = HidId {
+            product: 0x1234,
+            ..TOUCHPAD
+        };
+        assert!(find_hidraw(sysfs.path(), other).is_err());
+    }
+
+    #[test]
+    fn test_brightness_report() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hidraw0");
+        let mut backlight = HidrawBacklight {
+            node: FakeNode(File::create(&path).unwrap()),
+            path: path.clone(),
+        };
+        backlight.set_brightness(&default_level("half")).unwrap();
+        backlight.set_brightness(&Brightness::off()).unwrap();
+        assert_eq!(
+            std::fs::read(&path).unwrap(),
+            [0x0d, 0x14, 0x03, 24, 0xad, 0x0d, 0x14, 0x03, 0, 0xad]
+        );
+
+        // a plain file doesn't take feature reports
+        let mut backlight = HidrawBacklight::open(&path).unwrap();
+        assert!(backlight.set_brightness(&default_level("full")).is_err());
+    }
+}
@@ -0,0 +1,107 @@
+use evdev_rs::TimeVal;
+
+use crate::util::{CustomDuration, ElapsedSince};
+
+/// Tracks how long the numpad has gone unused.
+#[derive(Debug)]
+pub(crate) struct IdleTimer {
+    /// `None` if the idle timeout is disabled
+    timeout: Option<CustomDuration>,
+    /// When the numpad was last used. `None` while the timer isn't running.
+    last_used: Option<TimeVal>,
+}
+
+impl IdleTimer {
+    pub(crate) fn new(timeout: Option<CustomDuration>) -> Self {
+        Self {
+            timeout,
+            last_used: None,
+        }
+    }
+
+    /// (Re)start the timer from `now`. Does nothing if the timeout is disabled.
+    pub(crate) fn reset(&mut self, now: TimeVal) {
+        if self.timeout.is_some() {
+            self.last_used = Some(now);
+        }
+    }
+
+    pub(crate) fn stop(&mut self) {
+        self.last_used = None;
+    }
+
+    /// Time left until the timer expires, or `None` if it isn't running.
+    pub(crate) fn remaining(&self, now: TimeVal) -> Option<CustomDuration> {
+        let timeout = self.timeout?;
+        let last_used = self.last_used?;
+        Some(timeout.saturating_sub(now.elapsed_since(last_used)))
+    }
+
+    /// Check whether the timer has run out. The timer is stopped once it does.
+    pub(crate) fn check_expired(&mut self, now: TimeVal) -> bool {
+        let expired = self
+            .remaining(now)
+            .is_some_and(|remaining| remaining.as_millis_ceil() == 0);
+        if expired {
+            self.stop();
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::{Clock, FakeClock};
+
+    #[test]
+    fn test_disabled() {
+        let clock = FakeClock::new();
+        let mut timer = IdleTimer::new(None);
+        timer.reset(clock.now());
+        assert_eq!(timer.remaining(clock.now()), None);
+        clock.advance(CustomDuration::from_secs(3600));
+        assert!(!timer.check_expired(clock.now()));
+    }
+
+    #[test]
+    fn test_expires_after_timeout() {
+        let clock = FakeClock::new();
+        let mut timer = IdleTimer::new(Some(CustomDuration::from_secs(60)));
+        // not running until the numpad is used
+        assert_eq!(timer.remaining(clock.now()), None);
+        timer.reset(clock.now());
+
+        clock.advance(CustomDuration::from_secs(59));
+        assert_eq!(
+            timer.remaining(clock.now()),
+            Some(CustomDuration::from_secs(1))
+        );
+        assert!(!timer.check_expired(clock.now()));
+
+        clock.advance(CustomDuration::from_millis(1000));
+        assert!(timer.check_expired(clock.now()));
+        // only fires once
+        assert_eq!(timer.remaining(clock.now()), None);
+        assert!(!timer.check_expired(clock.now()));
+    }
+
+    #[test]
+    fn test_reset_extends_deadline() {
+        let clock = FakeClock::new();
+        let mut timer = IdleTimer::new(Some(CustomDuration::from_secs(60)));
+        timer.reset(clock.now());
+        clock.advance(CustomDuration::from_secs(45));
+        timer.reset(clock.now());
+        clock.advance(CustomDuration::from_secs(45));
+        assert!(!timer.check_expired(clock.now()));
+        assert_eq!(
+            timer.remaining(clock.now()),
+            Some(CustomDuration::from_secs(15))
+        );
+
+        timer.stop();
+        clock.advance(CustomDuration::from_secs(60));
+        assert!(!timer.check_expired(clock.now()));
+    }
+}
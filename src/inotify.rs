@@ -0,0 +1,115 @@
+use std::ffi::{CString, OsString};
+use std::io;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+/// A single filesystem event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct InotifyEvent {
+    /// The watch descriptor returned by [`Inotify::add_watch`]
+    pub(crate) wd: i32,
+    pub(crate) mask: u32,
+    /// Name of the file inside the watched directory, if any
+    pub(crate) name: Option<OsString>,
+}
+
+/// Minimal non-blocking wrapper over the inotify API.
+#[derive(Debug)]
+pub(crate) struct Inotify {
+    fd: OwnedFd,
+}
+
+impl Inotify {
+    pub(crate) fn new() -> Result<Self> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error()).context("Unable to init inotify");
+        }
+        // Safety: fd is valid and owned by us
+        Ok(Self {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        })
+    }
+
+    /// Watch the given path for the events in `mask`.
+    pub(crate) fn add_watch(&self, path: &Path, mask: u32) -> Result<i32> {
+        let c_path = CString::new(path.as_os_str().as_bytes())?;
+        let wd = unsafe { libc::inotify_add_watch(self.fd.as_raw_fd(), c_path.as_ptr(), mask) };
+        if wd < 0 {
+            return Err(io::Error::last_os_error())
+                .with_context(|| format!("Unable to watch {}", path.display()));
+        }
+        Ok(wd)
+    }
+
+    /// Read all the pending events, without blocking.
+    pub(crate) fn read_events(&self) -> Result<Vec<InotifyEvent>> {
+        const HEADER_LEN: usize = std::mem::size_of::<libc::inotify_event>();
+        let mut events = Vec::new();
+        let mut buf = [0u8; 4096];
+        loop {
+            let len = unsafe {
+                libc::read(
+                    self.fd.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                )
+            };
+            if len < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::WouldBlock {
+                    return Ok(events);
+                }
+                return Err(err).context("Unable to read inotify events");
+            }
+            let len = len as usize;
+            let mut offset = 0;
+            while offset + HEADER_LEN <= len {
+                // Safety: the kernel only writes complete events
+                let header: libc::inotify_event = unsafe {
+                    std::ptr::read_unaligned(buf[offset..].as_ptr() as *const libc::inotify_event)
+                };
+                let name_start = offset + HEADER_LEN;
+                let name_end = name_start + header.len as usize;
+                let name = (header.len > 0).then(|| {
+                    // the name is padded with NULs
+                    let raw = &buf[name_start..name_end];
+                    let raw = raw.split(|&b| b == 0).next().unwrap_or_default();
+                    OsString::from_vec(raw.to_vec())
+                });
+                events.push(InotifyEvent {
+                    wd: header.wd,
+                    mask: header.mask,
+                    name,
+                });
+                offset = name_end;
+            }
+        }
+    }
+}
+
+impl AsRawFd for Inotify {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+#[test]
+fn test_inotify() {
+    let dir = tempfile::tempdir().unwrap();
+    let inotify = Inotify::new().unwrap();
+    let wd = inotify
+        .add_watch(dir.path(), libc::IN_CREATE | libc::IN_ATTRIB)
+        .unwrap();
+    assert!(inotify.read_events().unwrap().is_empty());
+    std::fs::write(dir.path().join("event3"), "").unwrap();
+    let events = inotify.read_events().unwrap();
+    assert!(events.contains(&InotifyEvent {
+        wd,
+        mask: libc::IN_CREATE,
+        name: Some("event3".into())
+    }));
+}
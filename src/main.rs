@@ -1,35 +1,59 @@
+mod backlight;
+mod brightness;
 mod config;
+mod contacts;
+mod control;
+#[cfg(feature = "dbus")]
+mod dbus;
 mod devices;
+mod dmi;
 mod dummy_keyboard;
+mod engine;
+mod fade;
+mod gestures;
+mod hidraw;
+mod idle;
+mod inotify;
 mod numpad_layout;
+mod palm;
+mod proc_input;
+mod recording;
+mod signals;
+mod state_file;
 mod touchpad_i2c;
 mod util;
 
 use std::fmt::Display;
-use std::hint::unreachable_unchecked;
+use std::fs::File;
+use std::io::{BufReader, ErrorKind};
 use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
+use crate::backlight::{Backlight, open_backlight};
+use crate::brightness::Brightness;
 use crate::config::{Config, CustomCommand};
-use crate::devices::{get_touchpad_bbox, open_input_evdev, read_proc_input};
+use crate::control::{ControlSocket, Request, Response, send_request};
+#[cfg(feature = "dbus")]
+use crate::dbus::DbusService;
+use crate::devices::{Keyboard, NumpadDevices, TouchpadInfo, open_devices};
+use crate::dmi::{DmiInfo, detect_layout};
 use crate::dummy_keyboard::{DummyKeyboard, KeyEvents};
-use crate::numpad_layout::NumpadLayout;
-use crate::touchpad_i2c::{Brightness, TouchpadI2C};
-use crate::util::{CustomDuration, ElapsedSince};
+use crate::engine::{Action, Engine};
+use crate::hidraw::{HidId, find_hidraw};
+use crate::inotify::Inotify;
+use crate::numpad_layout::{LayoutConfig, SupportedLayout};
+use crate::recording::{Header, Record, Recorder};
+use crate::signals::Hangup;
+use crate::state_file::StateFile;
+use crate::util::{Clock, MonotonicClock};
 use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
 use evdev_rs::{
-    Device, DeviceWrapper, InputEvent, ReadFlag, TimeVal,
-    enums::{EV_ABS, EV_KEY, EV_LED, EV_MSC, EventCode},
+    Device, DeviceWrapper, ReadFlag,
+    enums::{EV_KEY, EV_LED, EventCode},
 };
-use log::{debug, error, info, trace, warn};
-
-#[derive(PartialEq, Default, Debug, Clone, Copy)]
-enum FingerState {
-    #[default]
-    Lifted,
-    TouchStart,
-    Touching,
-}
+use log::{LevelFilter, debug, error, info, trace, warn};
 
 #[derive(Debug, Default, Clone, Copy, PartialEq, PartialOrd)]
 pub struct Point {
@@ -49,211 +73,332 @@ impl Point {
     }
 }
 
-/// Represents the key being pressed currently
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub(crate) enum CurKey {
-    None,
-    Numlock,
-    Calc,
-    /// A key on the actual numpad bbox
-    Numpad(EV_KEY),
-}
-
-impl CurKey {
-    #[inline]
-    fn reset(&mut self) {
-        *self = Self::None;
-    }
-}
-
-impl Default for CurKey {
-    fn default() -> Self {
-        Self::None
-    }
-}
-
-#[derive(Debug)]
-struct TouchpadState {
-    pos: Point,
-    finger_state: FingerState,
-    numlock: bool,
-    cur_key: CurKey,
-    tap_started_at: TimeVal,
-    tap_start_pos: Point,
-    tapped_outside_numlock_bbox: bool,
-    finger_dragged_too_much: bool,
-    dragged_finger_lifted_at: TimeVal,
-    brightness: Brightness,
-    calc_open: bool,
-}
-
-impl TouchpadState {
-    #[inline]
-    fn toggle_numlock(&mut self) -> bool {
-        self.numlock = !self.numlock;
-        self.numlock
-    }
-}
-
-impl Default for TouchpadState {
-    fn default() -> Self {
-        Self {
-            pos: Default::default(),
-            finger_state: Default::default(),
-            numlock: false,
-            cur_key: Default::default(),
-            tap_started_at: TimeVal {
-                tv_sec: 0,
-                tv_usec: 0,
-            },
-            tap_start_pos: Default::default(),
-            tapped_outside_numlock_bbox: false,
-            finger_dragged_too_much: false,
-            dragged_finger_lifted_at: TimeVal {
-                tv_sec: 0,
-                tv_usec: 0,
-            },
-            brightness: Default::default(),
-            calc_open: false,
-        }
-    }
-}
-
+/// Connects the [`Engine`] to the actual devices.
 struct Numpad {
-    evdev: Device,
-    keyboard_evdev: Device,
-    touchpad_i2c: TouchpadI2C,
+    /// `None` while the touchpad is disconnected
+    evdev: Option<Device>,
+    /// Keyboards with a numlock LED, with the main one first.
+    /// Empty while all of them are disconnected.
+    keyboards: Vec<Keyboard>,
+    /// `None` while the touchpad is disconnected
+    backlight: Option<Box<dyn Backlight>>,
     dummy_kb: DummyKeyboard,
-    layout: NumpadLayout,
-    state: TouchpadState,
-    config: Config,
+    engine: Engine<MonotonicClock>,
+    /// Watches `/dev/input` for devices (re)appearing.
+    /// `None` after it failed, until it can be set up again.
+    dev_watcher: Option<Inotify>,
+    /// Saves the inputs of the engine, if recording
+    recorder: Option<Recorder>,
+    /// The touchpad that the engine was set up for
+    touchpad: TouchpadInfo,
+    /// Where the config came from, to reload it
+    config_source: ConfigSource,
+    /// `None` if disabled, or it couldn't be created
+    control: Option<ControlSocket>,
+    /// `None` if disabled, or the bus couldn't be reached
+    #[cfg(feature = "dbus")]
+    dbus: Option<DbusService>,
+    /// Tells when to reload the config
+    hangup: Hangup,
+    /// Watches the directory of the config file, if enabled
+    config_watcher: Option<Inotify>,
+    /// Keeps the state across restarts, if enabled
+    state_file: Option<StateFile>,
 }
 
 impl std::fmt::Debug for Numpad {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Numpad")
-            .field("evdev", &self.evdev.file())
-            .field("keyboard_evdev", &self.keyboard_evdev.file())
+            .field("evdev", &self.evdev.as_ref().map(Device::file))
+            .field(
+                "keyboards",
+                &self
+                    .keyboards
+                    .iter()
+                    .map(|kb| kb.evdev.file())
+                    .collect::<Vec<_>>(),
+            )
             .field("dummy_keyboard", &self.dummy_kb)
-            .field("touchpad_i2c", &self.touchpad_i2c)
-            .field("state", &self.state)
-            .field("layout", &self.layout)
+            .field("backlight", &self.backlight)
+            .field("engine", &self.engine)
             .finish()
     }
 }
 
 impl Numpad {
-    const HOLD_DURATION: CustomDuration = CustomDuration::from_millis(250);
-
-    /// Min Euclidean distance (squared) that a finger needs to move for a tap
-    /// to be changed into a drag.  
-    const TAP_JITTER_DIST: i32 = 10000;
+    /// How often to look for disconnected devices, in case we missed their inotify event.
+    const RECONNECT_INTERVAL_MS: i32 = 1000;
 
-    /// Min Euclidean distance (squared) that a finger needs to be dragged to
-    /// trigger the calculator key when numlock isn't active.
-    const CALC_DRAG_DIST: i32 = 90000;
+    // Slots of the fds polled by `process`
+    const TOUCHPAD_SLOT: usize = 0;
+    const DEV_WATCHER_SLOT: usize = 1;
+    const CONTROL_SLOT: usize = 2;
+    const DBUS_SLOT: usize = 3;
+    const HANGUP_SLOT: usize = 4;
+    const CONFIG_WATCHER_SLOT: usize = 5;
+    /// The keyboards start here, followed by the control clients
+    const KEYBOARDS_START: usize = 6;
 
     fn new(
-        evdev: Device,
-        keyboard_evdev: Device,
-        touchpad_i2c: TouchpadI2C,
+        devices: NumpadDevices,
+        touchpad: TouchpadInfo,
+        backlight: Box<dyn Backlight>,
         dummy_kb: DummyKeyboard,
-        layout: NumpadLayout,
-        config: Config,
-    ) -> Self {
-        Self {
-            evdev,
-            keyboard_evdev,
-            touchpad_i2c,
+        engine: Engine<MonotonicClock>,
+        config_source: ConfigSource,
+        recorder: Option<Recorder>,
+    ) -> Result<Self> {
+        let dev_watcher = Self::watch_dev_input()?;
+        let hangup = Hangup::install()?;
+        // the numpad works fine without it, so don't bail
+        let config = engine.config();
+        let control = config.control_socket().and_then(|path| {
+            match ControlSocket::bind(path, config.control_group()) {
+                Ok(control) => Some(control),
+                Err(err) => {
+                    error!("{:#}", err);
+                    None
+                }
+            }
+        });
+        let state_file = engine.config().state_dir().map(StateFile::open);
+        let mut numpad = Self {
+            evdev: Some(devices.touchpad),
+            keyboards: devices.keyboards,
+            backlight: Some(backlight),
             dummy_kb,
-            layout,
-            state: TouchpadState::default(),
-            config,
-        }
+            engine,
+            dev_watcher: Some(dev_watcher),
+            recorder,
+            touchpad,
+            config_source,
+            control,
+            #[cfg(feature = "dbus")]
+            dbus: None,
+            hangup,
+            config_watcher: None,
+            state_file,
+        };
+        #[cfg(feature = "dbus")]
+        numpad.connect_dbus();
+        numpad.update_config_watcher();
+        Ok(numpad)
     }
 
-    /// Toggle numlock when user presses the numlock bbox on touchpad.
-    fn toggle_numlock(&mut self) -> Result<()> {
-        if self.state.toggle_numlock() {
-            self.touchpad_i2c.set_brightness(self.state.brightness)?;
-            // don't grab touchpad - allow moving pointer even if active
-        } else {
-            self.touchpad_i2c.set_brightness(Brightness::Zero)?;
-            // we might still be grabbing the touchpad. release it.
-            self.ungrab();
+    /// Save an input of the engine, if recording.
+    fn record(&mut self, record: Record) {
+        let Some(recorder) = &mut self.recorder else {
+            return;
+        };
+        if let Err(err) = recorder.record(&record) {
+            error!("{:#}. Stopping the recording.", err);
+            self.recorder = None;
         }
-        // Tell the system that we want to toggle the numlock
-        self.dummy_kb.keypress(EV_KEY::KEY_NUMLOCK);
-        Ok(())
     }
 
-    /// Handle numlock pressed *from an external keyboard*.
+    fn is_connected(&self) -> bool {
+        self.evdev.is_some() && !self.keyboards.is_empty() && self.backlight.is_some()
+    }
+
+    /// Set the backlight brightness.
     ///
-    /// This is to keep the touchpad state in sync with system's numlock.
-    fn handle_numlock_pressed(&mut self, val: i32) -> Result<()> {
-        if val == 0 {
-            debug!("Setting numpad off");
-            self.state.numlock = false;
-            // we might still be grabbing the touchpad. release it.
-            self.ungrab();
-            self.touchpad_i2c.set_brightness(Brightness::Zero)
-        } else {
-            debug!("Setting numpad on {}", self.state.brightness);
-            self.state.numlock = true;
-            self.touchpad_i2c.set_brightness(self.state.brightness)
+    /// If the device has gone away, it is dropped so that it gets reopened later.
+    fn set_brightness(&mut self, brightness: Brightness) {
+        let Some(backlight) = &mut self.backlight else {
+            debug!("Backlight is disconnected, not setting brightness");
+            return;
+        };
+        if let Err(err) = backlight.set_brightness(&brightness) {
+            warn!("{:#}. Will try to reconnect.", err);
+            self.backlight = None;
         }
-        // The numlock has already been toggled on the system- no need to press
-        // the Num_Lock evkey.
     }
 
-    /// Query the initial state of numlock led from the system.
-    fn initialize_numlock(&mut self) -> Result<()> {
-        if self.config.disable_numpad() {
-            debug!("Skipping numlock init as numpad control is disabled");
-            return Ok(());
+    /// Perform the actions requested by the engine.
+    fn apply(&mut self, actions: Vec<Action>) {
+        for action in actions {
+            match action {
+                Action::KeyDown(keys) => self.dummy_kb.multi_keydown(&keys),
+                Action::KeyUp(keys) => self.dummy_kb.multi_keyup(&keys),
+                Action::ToggleNumlock => self.dummy_kb.keypress(EV_KEY::KEY_NUMLOCK),
+                Action::SetBrightness(brightness) => self.set_brightness(brightness),
+                Action::Grab => {
+                    if let Some(evdev) = &mut self.evdev {
+                        evdev
+                            .grab(evdev_rs::GrabMode::Grab)
+                            .unwrap_or_else(|err| warn!("Failed to grab {}", err));
+                    }
+                }
+                Action::Ungrab => {
+                    if let Some(evdev) = &mut self.evdev {
+                        evdev
+                            .grab(evdev_rs::GrabMode::Ungrab)
+                            .unwrap_or_else(|err| warn!("Failed to ungrab {}", err));
+                    }
+                }
+                Action::StartCalc => self.start_calc(),
+                Action::StopCalc => self.stop_calc(),
+            }
         }
-        let init_numlock = self
-            .keyboard_evdev
-            .event_value(&EventCode::EV_LED(EV_LED::LED_NUML));
-        match init_numlock {
+    }
+
+    /// Query the initial state of numlock led from the system.
+    fn initialize_numlock(&mut self) {
+        match self.keyboard_numlock() {
             Some(init_numlock) => {
-                if init_numlock != 0 {
-                    if self.config.disable_numlock_on_start() {
-                        self.dummy_kb.keypress(EV_KEY::KEY_NUMLOCK);
-                    } else {
-                        self.handle_numlock_pressed(init_numlock)?;
-                    }
+                let saved = self.state_file.as_ref().and_then(StateFile::saved).cloned();
+                if let Some(saved) = &saved {
+                    self.record(Record::SavedState(saved.clone()));
                 }
+                self.record(Record::InitNumlock(init_numlock));
+                let actions = self.engine.initialize_numlock(init_numlock, saved.as_ref());
+                self.apply(actions);
             }
             None => error!(
                 "Failed to get initial numlock state. \
                 There might be something wrong with evdev keyboard detection. \
                 {}",
-                self.keyboard_evdev.name().map_or_else(
-                    || "Unknown device".to_owned(),
-                    |n| format!("Using device: {}", n)
-                )
+                self.keyboards
+                    .first()
+                    .and_then(|kb| kb.evdev.name())
+                    .map_or_else(
+                        || "Unknown device".to_owned(),
+                        |n| format!("Using device: {}", n)
+                    )
             ),
         }
-        Ok(())
     }
 
-    fn grab(&mut self) {
-        debug!("Grabbing");
-        self.evdev
-            .grab(evdev_rs::GrabMode::Grab)
-            .unwrap_or_else(|err| warn!("Failed to grab {}", err));
+    /// Get the state of the numlock LED on the main keyboard.
+    fn keyboard_numlock(&self) -> Option<i32> {
+        self.keyboards
+            .first()?
+            .evdev
+            .event_value(&EventCode::EV_LED(EV_LED::LED_NUML))
+    }
+
+    /// Watch `/dev/input` for new device nodes.
+    fn watch_dev_input() -> Result<Inotify> {
+        let watcher = Inotify::new()?;
+        // udev creates the node first, and fixes up its permissions later
+        watcher.add_watch(Path::new("/dev/input"), libc::IN_CREATE | libc::IN_ATTRIB)?;
+        Ok(watcher)
+    }
+
+    /// Try to watch `/dev/input` again, after the watcher failed.
+    fn rearm_dev_watcher(&mut self) {
+        match Self::watch_dev_input() {
+            Ok(watcher) => {
+                info!("Watching /dev/input again");
+                self.dev_watcher = Some(watcher);
+            }
+            Err(err) => debug!("Can't watch /dev/input yet: {:#}", err),
+        }
+    }
+
+    /// Handle the devices that (re)appeared in `/dev/input`.
+    fn read_dev_events(&mut self) {
+        let Some(watcher) = &self.dev_watcher else {
+            return;
+        };
+        let events = match watcher.read_events() {
+            Ok(events) => events,
+            Err(err) => {
+                warn!("{:#}. Watching /dev/input again.", err);
+                self.dev_watcher = None;
+                self.rearm_dev_watcher();
+                // some events may have been lost, so look for the devices anyway
+                if !self.is_connected() {
+                    self.reconnect();
+                }
+                return;
+            }
+        };
+        trace!("Dev events {:?}", events);
+        for name in events.iter().filter_map(|ev| ev.name.as_ref()) {
+            self.on_device_added(&name.to_string_lossy());
+        }
+        if !self.is_connected() && !events.is_empty() {
+            self.reconnect();
+        }
     }
 
-    fn ungrab(&mut self) {
-        self.evdev
-            .grab(evdev_rs::GrabMode::Ungrab)
-            .unwrap_or_else(|err| warn!("Failed to ungrab {}", err));
+    /// Try to reopen any devices that have disconnected.
+    fn reconnect(&mut self) {
+        let devices = match open_devices(self.engine.config()) {
+            Ok(devices) => devices,
+            Err(err) => {
+                debug!("Devices not back yet: {:#}", err);
+                return;
+            }
+        };
+        let backlight = match open_backlight(self.engine.config().backlight(), &devices) {
+            Ok(backlight) => backlight,
+            Err(err) => {
+                debug!("Backlight not back yet: {:#}", err);
+                return;
+            }
+        };
+        info!("Devices reconnected");
+        // keep the devices that are still connected, to not lose their state
+        if self.evdev.is_none() {
+            match TouchpadInfo::read(&devices.touchpad, self.touchpad.detected) {
+                Ok(touchpad) => {
+                    self.engine.on_touchpad_reconnected(&touchpad);
+                    self.touchpad = touchpad;
+                }
+                Err(err) => {
+                    debug!("Touchpad not ready yet: {:#}", err);
+                    return;
+                }
+            }
+            self.evdev = Some(devices.touchpad);
+        }
+        for keyboard in devices.keyboards {
+            if !self.keyboards.iter().any(|kb| kb.ev_id == keyboard.ev_id) {
+                self.keyboards.push(keyboard);
+            }
+        }
+        self.backlight = Some(backlight);
+        let actions = self.engine.restore_state(self.keyboard_numlock());
+        self.apply(actions);
+    }
+
+    fn on_touchpad_lost(&mut self, err: std::io::Error) {
+        warn!("Touchpad disconnected: {}", err);
+        self.evdev = None;
+        let actions = self.engine.on_touchpad_lost();
+        self.apply(actions);
+        // the contacts will be resynced when the touchpad is back
+        // the backlight usually goes away along with the touchpad
+        self.backlight = None;
+    }
+
+    fn on_keyboard_lost(&mut self, idx: usize, err: std::io::Error) {
+        let keyboard = self.keyboards.remove(idx);
+        warn!(
+            "Keyboard {:?} disconnected: {}",
+            keyboard.evdev.name().unwrap_or_default(),
+            err
+        );
+    }
+
+    /// Start tracking a newly plugged in keyboard, if it has a numlock LED.
+    fn on_device_added(&mut self, name: &str) {
+        let Some(ev_id) = name.strip_prefix("event").and_then(|id| id.parse().ok()) else {
+            return;
+        };
+        if self.keyboards.iter().any(|kb| kb.ev_id == ev_id) {
+            return;
+        }
+        // our own dummy keyboard doesn't have a numlock LED, so it will be skipped here
+        if let Some(keyboard) = Keyboard::open_if_has_numlock(ev_id) {
+            self.keyboards.push(keyboard);
+        }
     }
 
     fn start_calc(&mut self) {
-        debug!("Starting calc");
-        match self.config.calc_start_command() {
+        match self.engine.config().calc_start_command() {
             CustomCommand::Keys(keys) => self.dummy_kb.multi_keypress(keys.as_slice()),
             CustomCommand::Command { cmd, args } => {
                 debug!("Running command {} with args {:?}", cmd, args);
@@ -279,9 +424,7 @@ impl Numpad {
     }
 
     fn stop_calc(&mut self) {
-        if let Some(stop_cmd) = self.config.calc_stop_command() {
-            debug!("Stopping calc");
-
+        if let Some(stop_cmd) = self.engine.config().calc_stop_command() {
             match stop_cmd {
                 CustomCommand::Keys(keys) => self.dummy_kb.multi_keypress(keys.as_slice()),
                 CustomCommand::Command { cmd, args } => {
@@ -305,236 +448,609 @@ impl Numpad {
         }
     }
 
-    fn on_lift(&mut self) {
-        // end of tap
-        debug!("End tap");
-        if self.state.cur_key == CurKey::Calc
-            && self.state.pos.dist_sq(self.state.tap_start_pos) >= Self::CALC_DRAG_DIST
-        {
-            if !self.state.calc_open {
-                self.start_calc();
-            } else {
-                self.stop_calc();
+    /// Reload the config file, keeping the current one if the new one is invalid.
+    ///
+    /// The devices, the control socket and the D-Bus connection are kept as they are.
+    fn reload_config(&mut self) -> Result<()> {
+        let (config_text, config) = self.config_source.load()?;
+        let actions = self.engine.reload(config, &self.touchpad)?;
+        self.record(Record::Config {
+            time: self.engine.clock().now(),
+            config: config_text,
+        });
+        // release the pressed keys on the current keyboard
+        self.apply(actions);
+        let (layout, autorepeat) = (self.engine.layout(), self.engine.config().autorepeat());
+        if !self.dummy_kb.fits(layout, autorepeat) {
+            info!("Keys changed, creating the dummy keyboard again");
+            match DummyKeyboard::new(layout, autorepeat) {
+                Ok(kb) => self.dummy_kb = kb,
+                Err(err) => error!("{:#}. Keeping the old dummy keyboard.", err),
             }
-            self.state.calc_open = !self.state.calc_open;
         }
+        self.update_config_watcher();
+        info!("Config reloaded");
+        Ok(())
+    }
 
-        if self.state.finger_state == FingerState::Touching {
-            if let CurKey::Numpad(key) = self.state.cur_key {
-                debug!("Keyup {:?}", key);
+    /// Reload the config after a SIGHUP or a change of the file.
+    fn on_config_changed(&mut self) {
+        if let Err(err) = self.reload_config() {
+            error!("{:#}. Keeping the current config.", err);
+        }
+    }
 
-                if self.layout.needs_multikey(key) {
-                    self.dummy_kb.multi_keyup(&self.layout.multikeys(key));
-                } else {
-                    self.dummy_kb.keyup(key);
-                }
-                // if we ungrab here, it causes the pointer to jump
-                // so we only ungrab when finger is dragged
+    /// Start or stop watching the config file, as the config says.
+    fn update_config_watcher(&mut self) {
+        if !self.engine.config().watch_config() {
+            self.config_watcher = None;
+            return;
+        }
+        if self.config_watcher.is_some() {
+            return;
+        }
+        // editors often replace the file instead of writing to it, so watch its directory
+        let dir = self
+            .config_source
+            .path()
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        let watcher = Inotify::new().and_then(|watcher| {
+            watcher.add_watch(dir, libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO)?;
+            Ok(watcher)
+        });
+        match watcher {
+            Ok(watcher) => self.config_watcher = Some(watcher),
+            Err(err) => warn!("{:#}. The config won't be reloaded when it changes.", err),
+        }
+    }
+
+    /// Whether the config file is among the changed files of the watcher.
+    fn config_file_changed(&mut self) -> bool {
+        let Some(watcher) = &self.config_watcher else {
+            return false;
+        };
+        let file_name = self.config_source.path().file_name();
+        match watcher.read_events() {
+            Ok(events) => events.iter().any(|ev| ev.name.as_deref() == file_name),
+            Err(err) => {
+                warn!("{:#}. Keeping the current config.", err);
+                // set up a fresh watcher instead of the broken one
+                self.config_watcher = None;
+                self.update_config_watcher();
+                false
             }
         }
-        self.state.cur_key.reset();
-        self.state.finger_state = FingerState::Lifted;
-    }
-
-    fn on_tap(&mut self, time: TimeVal) {
-        if self.state.finger_state == FingerState::Lifted {
-            // start of tap
-            debug!("Start tap");
-            self.state.finger_state = FingerState::TouchStart;
-            self.state.tap_started_at = time;
-            self.state.tap_start_pos = self.state.pos;
-            self.state.tapped_outside_numlock_bbox = false;
-            self.state.finger_dragged_too_much = false;
-            if self.state.numlock {
-                self.state.cur_key = match self.layout.get_key(self.state.pos) {
-                    Some(key) => {
-                        self.grab();
-                        self.state.finger_state = FingerState::Touching;
-
-                        debug!("Keydown {:?}", key);
-                        if self.layout.needs_multikey(key) {
-                            self.dummy_kb.multi_keydown(&self.layout.multikeys(key));
-                        } else {
-                            self.dummy_kb.keydown(key);
-                        }
-                        CurKey::Numpad(key)
+    }
+
+    fn handle_request(&mut self, request: Request) -> Response {
+        match request {
+            Request::State => return Response::State(self.engine.status()),
+            Request::Reload => {
+                return match self.reload_config() {
+                    Ok(()) => Response::Ok,
+                    Err(err) => {
+                        error!("{:#}", err);
+                        Response::Error(format!("{:#}", err))
                     }
-                    None => CurKey::None,
                 };
             }
+            _ => (),
         }
-        if self.layout.in_numlock_bbox(self.state.pos) && !self.config.disable_numpad() {
-            debug!("In numlock - start");
-            self.state.finger_state = FingerState::Touching;
-            self.state.cur_key = CurKey::Numlock;
-        } else {
-            if self.layout.in_calc_bbox(self.state.pos) {
-                debug!("In calc - start");
-                self.state.finger_state = FingerState::Touching;
-                self.state.cur_key = CurKey::Calc;
+        self.record(Record::Request {
+            time: self.engine.clock().now(),
+            request: request.clone(),
+        });
+        match self.engine.handle_request(&request) {
+            Ok(actions) => {
+                self.apply(actions);
+                Response::Ok
             }
-            self.state.tapped_outside_numlock_bbox = true
+            Err(err) => Response::Error(format!("{:#}", err)),
         }
     }
 
-    fn handle_touchpad_event(&mut self, ev: InputEvent) -> Result<()> {
-        // TODO: Double-taps when numpad is active should not be propagated.
-        //       Need to grab/ungrab the device intelligently.
-
-        // no need to trace timestamp events - too noisy
-        if !matches!(ev.event_code, EventCode::EV_MSC(EV_MSC::MSC_TIMESTAMP)) {
-            trace!("TP {:?} {}", ev.event_code, ev.value);
-        }
-        match ev.event_code {
-            EventCode::EV_ABS(EV_ABS::ABS_MT_POSITION_X) => {
-                self.state.pos.x = ev.value;
-            }
-            EventCode::EV_ABS(EV_ABS::ABS_MT_POSITION_Y) => {
-                self.state.pos.y = ev.value;
-            }
-            EventCode::EV_KEY(EV_KEY::BTN_TOOL_FINGER) if ev.value == 0 => {
-                if !self.state.finger_dragged_too_much {
-                    // only call on_lift if we did not already call it as a result of finger drag
-                    self.on_lift();
-                } else {
-                    self.state.dragged_finger_lifted_at = ev.time;
-                }
-            }
-            EventCode::EV_KEY(EV_KEY::BTN_TOOL_FINGER) if ev.value == 1 => {
-                if !self.state.finger_dragged_too_much
-                    || ev.time.elapsed_since(self.state.dragged_finger_lifted_at)
-                        >= Self::HOLD_DURATION
-                {
-                    self.on_tap(ev.time);
-                }
+    /// Read from the control client at `idx`, and answer its request once it has arrived.
+    fn read_control_client(&mut self, idx: usize) {
+        let Some((client, request)) = self.control.as_mut().and_then(|c| c.read_client(idx)) else {
+            return;
+        };
+        let response = match request {
+            Ok(request) => {
+                debug!("Control request: {}", request);
+                self.handle_request(request)
             }
-            EventCode::EV_MSC(EV_MSC::MSC_TIMESTAMP) => {
-                // The toggle should happen automatically after HOLD_DURATION, even if user is
-                // still touching the numpad bbox.
-                if self.state.finger_state == FingerState::TouchStart {
-                    trace!("Touch {}", self.state.pos);
-                }
+            Err(err) => Response::Error(format!("{:#}", err)),
+        };
+        client.respond(&response);
+    }
 
-                if self.state.finger_state == FingerState::Touching
-                    && !self.state.tapped_outside_numlock_bbox
-                    && !self.config.disable_numpad()
-                {
-                    if self.layout.in_numlock_bbox(self.state.pos) {
-                        if ev.time.elapsed_since(self.state.tap_started_at) >= Self::HOLD_DURATION {
-                            debug!("Hold finish - toggle numlock");
-                            self.toggle_numlock()?;
-                            // If user doesn't lift the finger quickly, we don't want to keep
-                            // toggling, so assume finger was moved.
-                            // Can't do finger_state = Lifted, since that would start another tap
-                            self.state.finger_state = FingerState::TouchStart;
-                        }
-                    } else {
-                        self.state.tapped_outside_numlock_bbox = true;
-                    }
+    /// Read all the pending touchpad events.
+    fn read_touchpad_events(&mut self) {
+        loop {
+            let Some(evdev) = &self.evdev else {
+                return;
+            };
+            match evdev.next_event(ReadFlag::NORMAL) {
+                Ok((_, ev)) => {
+                    self.record(Record::Touchpad(ev.clone()));
+                    let actions = self.engine.handle_touchpad_event(&ev);
+                    self.apply(actions);
                 }
-                if self.state.numlock
-                    && self.state.cur_key == CurKey::Calc
-                    && self.layout.in_calc_bbox(self.state.pos)
-                    && ev.time.elapsed_since(self.state.tap_started_at) >= Self::HOLD_DURATION
-                {
-                    debug!("Hold finish - cycle brightness");
-                    self.touchpad_i2c
-                        .set_brightness(self.state.brightness.cycle())?;
-                    self.state.cur_key.reset();
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return,
+                Err(err) => {
+                    self.on_touchpad_lost(err);
+                    return;
                 }
             }
-            _ => (),
         }
+    }
 
-        // if the finger drags too much, stop the tap
-        // TODO: Use the same logic for numlock bbox instead of `tapped_outside_numlock_bbox`
-        if self.state.numlock
-            && self.state.finger_state == FingerState::Touching
-            && self.state.cur_key != CurKey::Calc // we are fine if finger drags on calc box
-            && self.state.tap_start_pos.dist_sq(self.state.pos) > Self::TAP_JITTER_DIST
-        {
-            debug!("Moved too much");
-            self.state.finger_dragged_too_much = true;
-            self.ungrab();
-            self.on_lift();
+    /// Read all the pending events of the keyboard at `idx`.
+    fn read_keyboard_events(&mut self, idx: usize) {
+        loop {
+            let ev = match self.keyboards[idx].evdev.next_event(ReadFlag::NORMAL) {
+                Ok((_, ev)) => ev,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return,
+                Err(err) => {
+                    self.on_keyboard_lost(idx, err);
+                    return;
+                }
+            };
+            let internal = self.keyboards[idx].internal;
+            if let Some(record) = Record::keyboard(&ev, internal) {
+                self.record(record);
+            }
+            let actions = self.engine.handle_keyboard_event(&ev, internal);
+            self.apply(actions);
         }
-        Ok(())
     }
 
     fn process(&mut self) -> Result<()> {
-        self.initialize_numlock()?;
+        self.initialize_numlock();
 
-        let tp_fd = libc::pollfd {
-            fd: self.evdev.file().as_raw_fd(),
-            events: libc::POLLIN,
-            revents: 0,
-        };
-        let kb_fd = libc::pollfd {
-            fd: self.keyboard_evdev.file().as_raw_fd(),
-            events: if self.config.disable_numpad() {
+        loop {
+            if self.dev_watcher.is_none() {
+                self.rearm_dev_watcher();
+            }
+            // a negative fd is ignored by poll, so a disconnected touchpad is skipped
+            let tp_fd = libc::pollfd {
+                fd: self.evdev.as_ref().map_or(-1, |dev| dev.file().as_raw_fd()),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let watcher_fd = libc::pollfd {
+                fd: self.dev_watcher.as_ref().map_or(-1, AsRawFd::as_raw_fd),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let kb_events = if self.engine.config().disable_numpad() {
                 0
             } else {
                 libc::POLLIN
-            },
-            revents: 0,
-        };
-        let mut fds = [tp_fd, kb_fd];
+            };
+            let control_fd = libc::pollfd {
+                fd: self.control.as_ref().map_or(-1, AsRawFd::as_raw_fd),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            #[cfg(feature = "dbus")]
+            let dbus_fd = self.dbus.as_ref().map_or(-1, AsRawFd::as_raw_fd);
+            #[cfg(not(feature = "dbus"))]
+            let dbus_fd = -1;
+            let dbus_fd = libc::pollfd {
+                fd: dbus_fd,
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let hangup_fd = libc::pollfd {
+                fd: self.hangup.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let config_watcher_fd = libc::pollfd {
+                fd: self.config_watcher.as_ref().map_or(-1, AsRawFd::as_raw_fd),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let mut fds = vec![tp_fd; Self::KEYBOARDS_START];
+            fds[Self::DEV_WATCHER_SLOT] = watcher_fd;
+            fds[Self::CONTROL_SLOT] = control_fd;
+            fds[Self::DBUS_SLOT] = dbus_fd;
+            fds[Self::HANGUP_SLOT] = hangup_fd;
+            fds[Self::CONFIG_WATCHER_SLOT] = config_watcher_fd;
+            fds.extend(self.keyboards.iter().map(|kb| libc::pollfd {
+                fd: kb.evdev.file().as_raw_fd(),
+                events: kb_events,
+                revents: 0,
+            }));
+            let clients_start = fds.len();
+            let clients = self
+                .control
+                .as_ref()
+                .map_or(&[][..], ControlSocket::clients);
+            fds.extend(clients.iter().map(|client| libc::pollfd {
+                fd: client.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            }));
+            let timeout = self.poll_timeout();
 
-        loop {
-            match unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as _, -1) } {
-                0 => (), // timeout, TODO: disable numpad if idle (no touches) for 1 minute
-                1 | 2 => {
-                    if fds[0].revents & libc::POLLIN != 0 {
-                        // read until no more events
-                        while let Ok((_, ev)) = self.evdev.next_event(ReadFlag::NORMAL) {
-                            self.handle_touchpad_event(ev)?;
+            match unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as _, timeout) } {
+                0 => {
+                    if !self.is_connected() {
+                        self.reconnect();
+                    }
+                }
+                -1 => {
+                    let err = std::io::Error::last_os_error();
+                    if err.kind() != ErrorKind::Interrupted {
+                        return Err(err).context("Error while polling devices");
+                    }
+                }
+                _ => {
+                    // POLLHUP/POLLERR are reported when the device goes away, and the
+                    // read will then fail with ENODEV.
+                    if fds[Self::TOUCHPAD_SLOT].revents != 0 {
+                        self.read_touchpad_events();
+                    }
+                    // go in reverse, so that removing a keyboard doesn't shift the rest
+                    let kb_fds = &fds[Self::KEYBOARDS_START..clients_start];
+                    for (idx, kb_fd) in kb_fds.iter().enumerate().rev() {
+                        if kb_fd.revents != 0 {
+                            self.read_keyboard_events(idx);
                         }
                     }
-                    if fds[1].revents & libc::POLLIN != 0 {
-                        while let Ok((_, ev)) = self.keyboard_evdev.next_event(ReadFlag::NORMAL) {
-                            // Note: We only listen to the LED event, and not the numlock event.
-                            // While most environments keep them in sync, it is technically possible
-                            // to change the led state without changing the numlock state.
-                            //
-                            // But there is no simple way for us to figure out the actual numlock
-                            // state. We would need to bring in Xlib (and equivalent for wayland)
-                            // and query it to get the numlock state.
-                            //
-                            // So, we only listen for LED changes, hoping that it reflects numlock state
-                            if let EventCode::EV_LED(EV_LED::LED_NUML) = ev.event_code {
-                                self.handle_numlock_pressed(ev.value)?;
-                            }
-                            trace!("KB {}, {}", ev.event_code, ev.value);
+                    for (idx, client_fd) in fds[clients_start..].iter().enumerate().rev() {
+                        if client_fd.revents != 0 {
+                            self.read_control_client(idx);
+                        }
+                    }
+                    if fds[Self::CONTROL_SLOT].revents & libc::POLLIN != 0 {
+                        if let Some(control) = &mut self.control {
+                            control.accept();
                         }
                     }
+                    #[cfg(feature = "dbus")]
+                    if fds[Self::DBUS_SLOT].revents & libc::POLLIN != 0 {
+                        self.handle_dbus_calls();
+                    }
+                    let hangup = fds[Self::HANGUP_SLOT].revents & libc::POLLIN != 0
+                        && self.hangup.received();
+                    let file_changed = fds[Self::CONFIG_WATCHER_SLOT].revents & libc::POLLIN != 0
+                        && self.config_file_changed();
+                    if hangup || file_changed {
+                        info!("Reloading the config");
+                        self.on_config_changed();
+                    }
+                    if fds[Self::DEV_WATCHER_SLOT].revents & libc::POLLIN != 0 {
+                        self.read_dev_events();
+                    }
                 }
-                // we have only given 2 fds, so max return val of poll can be 2
-                _ => unsafe { unreachable_unchecked() },
             }
+            let actions = self.engine.handle_timeouts();
+            self.apply(actions);
+            self.save_state();
+            #[cfg(feature = "dbus")]
+            self.publish_properties();
         }
     }
+
+    /// Save the brightness and numlock state, if they changed.
+    fn save_state(&mut self) {
+        if let Some(state_file) = &mut self.state_file {
+            if let Err(err) = state_file.save(self.engine.saved_state()) {
+                warn!("{:#}", err);
+            }
+        }
+    }
+
+    /// Time to wait for events, in milliseconds. `-1` waits forever.
+    fn poll_timeout(&self) -> i32 {
+        let timer = self
+            .engine
+            .next_timeout()
+            .map(|remaining| remaining.as_millis_ceil().min(i32::MAX as u64) as i32);
+        let retry = !self.is_connected() || self.dev_watcher.is_none();
+        let reconnect = retry.then_some(Self::RECONNECT_INTERVAL_MS);
+        timer.into_iter().chain(reconnect).min().unwrap_or(-1)
+    }
 }
 
-fn main() -> Result<()> {
-    env_logger::init();
+#[cfg(feature = "dbus")]
+impl Numpad {
+    /// Serve the numpad on the bus in the config.
+    fn connect_dbus(&mut self) {
+        // the numpad works fine without it, so don't bail
+        match DbusService::connect(self.engine.config().dbus(), self.dbus_properties()) {
+            Ok(dbus) => self.dbus = dbus,
+            Err(err) => error!("{:#}", err),
+        }
+    }
+
+    fn dbus_properties(&self) -> dbus::Properties {
+        let layout = match self.engine.config().layout() {
+            Some(layout) => layout.name(),
+            None => self
+                .touchpad
+                .detected
+                .map(|layout| format!("{:?}", layout))
+                .unwrap_or_default(),
+        };
+        let status = self.engine.status();
+        dbus::Properties {
+            numlock: status.numlock,
+            brightness: status.brightness.to_string(),
+            layout,
+        }
+    }
 
+    /// Carry out all the pending D-Bus method calls.
+    fn handle_dbus_calls(&mut self) {
+        let calls = self.dbus.as_ref().map(DbusService::take_calls);
+        for call in calls.into_iter().flatten() {
+            debug!("D-Bus request: {}", call.request);
+            let response = self.handle_request(call.request.clone());
+            // so that the caller sees the new values as soon as it returns
+            self.publish_properties();
+            call.respond(response);
+        }
+    }
+
+    fn publish_properties(&self) {
+        if let Some(dbus) = &self.dbus {
+            dbus.publish(self.dbus_properties());
+        }
+    }
+}
+
+/// Linux app to allow using the numpad that is overlayed on various Asus Laptop touchpads.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Path of the config file [default: /etc/xdg/asus_numpad.toml]
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    /// Use this layout, instead of the configured or detected one
+    #[arg(long, global = true)]
+    layout: Option<SupportedLayout>,
+    /// One of off, error, warn, info, debug or trace. Overrides `RUST_LOG`
+    #[arg(long, global = true)]
+    log_level: Option<LevelFilter>,
+    #[command(subcommand)]
+    command: Option<CliCommand>,
+}
+
+#[derive(Debug, Subcommand)]
+enum CliCommand {
+    /// Run the daemon. This is the default
+    Run,
+    /// Run the daemon, while saving the touchpad and keyboard events to a file
+    Record { file: PathBuf },
+    /// Print what the numpad would do for a recording, without any devices.
+    /// The config saved in the recording is used
+    Replay { file: PathBuf },
+    /// List the supported layouts
+    ListLayouts,
+    /// Print the devices that would be used, and the detected laptop model
+    Detect,
+    /// Check the config file for errors
+    CheckConfig,
+    /// Control the running daemon
+    Ctl {
+        #[command(subcommand)]
+        request: Request,
+    },
+}
+
+/// Where to read the config from, as given on the command line.
+#[derive(Debug, Clone)]
+struct ConfigSource {
+    path: Option<PathBuf>,
+    /// Overrides the layout in the file
+    layout: Option<SupportedLayout>,
+}
+
+impl ConfigSource {
     // Follows XDG Base Dir Spec
-    const CONFIG_PATH: &str = "/etc/xdg/asus_numpad.toml";
-    let v = std::fs::read(CONFIG_PATH)?;
-    let config: Config = toml::from_str(&String::from_utf8(v)?)?;
-    info!("Config: {:?}", config);
-    let layout_name = config.layout();
-
-    let (keyboard_ev_id, touchpad_ev_id, i2c_id) =
-        read_proc_input().context("Couldn't get proc input devices")?;
-    let touchpad_dev = open_input_evdev(touchpad_ev_id)?;
-    let keyboard_dev = open_input_evdev(keyboard_ev_id)?;
-    let bbox = get_touchpad_bbox(&touchpad_dev)?;
-    info!("BBox: {:?}", bbox);
-    let layout = NumpadLayout::from_supported_layout(layout_name, bbox)?;
-    let kb = DummyKeyboard::new(&layout)?;
-    let touchpad_i2c = TouchpadI2C::new(i2c_id)?;
-    let mut numpad = Numpad::new(touchpad_dev, keyboard_dev, touchpad_i2c, kb, layout, config);
-    numpad.process()?;
+    const DEFAULT_PATH: &str = "/etc/xdg/asus_numpad.toml";
+
+    fn path(&self) -> &Path {
+        self.path
+            .as_deref()
+            .unwrap_or(Path::new(Self::DEFAULT_PATH))
+    }
+
+    /// Read the config file. Returns its contents along with the parsed config.
+    fn load(&self) -> Result<(String, Config)> {
+        let v = match &self.path {
+            Some(path) => std::fs::read_to_string(path)
+                .with_context(|| format!("Could not read config file {}", path.display()))?,
+            None => match std::fs::read_to_string(Self::DEFAULT_PATH) {
+                // all the options have defaults, so the default file is optional
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+                v => {
+                    v.with_context(|| format!("Could not read config file {}", Self::DEFAULT_PATH))?
+                }
+            },
+        };
+        let mut config: Config = toml::from_str(&v).context("Invalid config")?;
+        if let Some(layout) = self.layout {
+            config.set_layout(LayoutConfig::Preset(layout));
+        }
+        info!("Config: {:?}", config);
+        Ok((v, config))
+    }
+}
+
+/// Detect the laptop model, if the configured layout can make use of it.
+fn detect_model(config: &Config, touchpad_name: Option<&str>) -> Option<SupportedLayout> {
+    if !config.layout().is_none_or(LayoutConfig::wants_detected) {
+        return None;
+    }
+    let dmi = DmiInfo::read(Path::new("/sys")).unwrap_or_else(|err| {
+        warn!("{:#}", err);
+        Default::default()
+    });
+    let detected = detect_layout(&dmi, touchpad_name);
+    info!("Detected layout: {:?}", detected);
+    detected
+}
+
+fn run(config_source: ConfigSource, record: Option<&Path>) -> Result<()> {
+    let (config_text, config) = config_source.load()?;
+    let devices = open_devices(&config)?;
+    let touchpad_name = devices.touchpad.name().unwrap_or_default().to_owned();
+    let detected = detect_model(&config, Some(&touchpad_name));
+
+    let touchpad = TouchpadInfo::read(&devices.touchpad, detected)?;
+    info!("BBox: {:?}", touchpad.bbox);
+    let engine = Engine::from_config(config, &touchpad, MonotonicClock)?;
+    let recorder = match record {
+        Some(path) => {
+            let header = Header {
+                config: config_text,
+                touchpad: touchpad.clone(),
+                started_at: engine.clock().now(),
+            };
+            info!("Recording to {}", path.display());
+            Some(Recorder::create(path, &header)?)
+        }
+        None => None,
+    };
+    let kb = DummyKeyboard::new(engine.layout(), engine.config().autorepeat())?;
+    let backlight = open_backlight(engine.config().backlight(), &devices)?;
+    let mut numpad = Numpad::new(
+        devices,
+        touchpad,
+        backlight,
+        kb,
+        engine,
+        config_source,
+        recorder,
+    )?;
+    numpad.process()
+}
+
+fn replay(path: &Path, layout: Option<SupportedLayout>) -> Result<()> {
+    let file =
+        File::open(path).with_context(|| format!("Could not open recording {}", path.display()))?;
+    for (time, action) in recording::replay(BufReader::new(file), layout)? {
+        println!("{}.{:06} {:?}", time.tv_sec, time.tv_usec, action);
+    }
+    Ok(())
+}
+
+fn list_layouts() {
+    for layout in SupportedLayout::ALL {
+        println!("{:?}", layout);
+    }
+}
+
+fn detect(config: &Config) -> Result<()> {
+    let dmi = DmiInfo::read(Path::new("/sys")).unwrap_or_else(|err| {
+        warn!("{:#}", err);
+        Default::default()
+    });
+    println!("Product: {}", dmi.product_name);
+    println!("Board: {}", dmi.board_name);
+    let devices = open_devices(config)?;
+    let touchpad = TouchpadInfo::read(&devices.touchpad, None)?;
+    println!("Touchpad: {:?}", touchpad.name);
+    println!("  Size: {:?}", touchpad.bbox);
+    println!("  Resolution: {:?}", touchpad.resolution);
+    match devices.i2c_id {
+        Some(i2c_id) => println!("Touchpad I2C: /dev/i2c-{}", i2c_id),
+        None => println!("Touchpad I2C: not found"),
+    }
+    let hid_id = HidId::of(&devices.touchpad);
+    match find_hidraw(Path::new("/sys"), hid_id) {
+        Ok(path) => println!("Touchpad hidraw: {}", path.display()),
+        Err(err) => println!("Touchpad hidraw: {:#}", err),
+    }
+    for keyboard in &devices.keyboards {
+        println!(
+            "Keyboard: {:?} (/dev/input/event{}{})",
+            keyboard.evdev.name().unwrap_or_default(),
+            keyboard.ev_id,
+            if keyboard.internal { ", internal" } else { "" }
+        );
+    }
+    match detect_layout(&dmi, Some(&touchpad.name)) {
+        Some(layout) => println!("Detected layout: {:?}", layout),
+        None => println!("Detected layout: none, please set it in the config file"),
+    }
+    Ok(())
+}
+
+/// Check the parts of the config that don't depend on the touchpad.
+fn check_config(config: &Config) -> Result<()> {
+    let detected = detect_model(config, None);
+    match config.layout() {
+        Some(layout) => {
+            layout.spec(detected).context("Invalid layout")?;
+        }
+        None if detected.is_none() => warn!(
+            "The laptop model could not be detected from DMI. \
+            Unless the touchpad name gives it away, the layout must be set in the config."
+        ),
+        None => (),
+    }
+    println!("Config is valid");
+    Ok(())
+}
+
+/// Send a request to the running daemon, and print its response.
+fn ctl(config: &Config, request: &Request) -> Result<()> {
+    let path = config
+        .control_socket()
+        .context("The control socket is disabled in the config")?;
+    let response = send_request(path, request)?;
+    if response != "ok" {
+        println!("{}", response);
+    }
     Ok(())
 }
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let mut logger = env_logger::Builder::from_default_env();
+    if let Some(level) = cli.log_level {
+        logger.filter_level(level);
+    }
+    logger.init();
+
+    let command = cli.command.unwrap_or(CliCommand::Run);
+    match command {
+        CliCommand::Replay { file } => return replay(&file, cli.layout),
+        CliCommand::ListLayouts => {
+            list_layouts();
+            return Ok(());
+        }
+        _ => (),
+    }
+    let config_source = ConfigSource {
+        path: cli.config,
+        layout: cli.layout,
+    };
+    match command {
+        CliCommand::Run => run(config_source, None),
+        CliCommand::Record { file } => run(config_source, Some(&file)),
+        CliCommand::Detect => detect(&config_source.load()?.1),
+        CliCommand::CheckConfig => check_config(&config_source.load()?.1),
+        CliCommand::Ctl { request } => ctl(&config_source.load()?.1, &request),
+        CliCommand::Replay { .. } | CliCommand::ListLayouts => unreachable!(),
+    }
+}
+
+#[test]
+fn test_cli() {
+    use clap::CommandFactory;
+    Cli::command().debug_assert();
+
+    let cli = Cli::try_parse_from(["asus-numpad", "check-config", "--layout", "gx701"]).unwrap();
+    assert!(matches!(cli.command, Some(CliCommand::CheckConfig)));
+    assert_eq!(cli.layout, Some(SupportedLayout::GX701));
+    assert!(Cli::try_parse_from(["asus-numpad", "--layout", "UX9000"]).is_err());
+}
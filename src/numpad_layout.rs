@@ -1,21 +1,63 @@
 use std::fmt::Debug;
 use std::hint::unreachable_unchecked;
+use std::str::FromStr;
 
-use anyhow::Result;
+use anyhow::{Result, anyhow, ensure};
 use evdev_rs::enums::EV_KEY;
 use serde::{Deserialize, Serialize};
 
 use crate::Point;
 
-#[derive(Debug, Default, Clone, Copy)]
-struct Margins {
+/// Fraction of the touchpad's range to leave out on each side of a bbox.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Margins {
     top: f32,
     bottom: f32,
     left: f32,
     right: f32,
 }
 
-#[derive(Debug)]
+impl Margins {
+    const fn new(top: f32, bottom: f32, left: f32, right: f32) -> Self {
+        Self {
+            top,
+            bottom,
+            left,
+            right,
+        }
+    }
+
+    fn validate(&self, name: &str) -> Result<()> {
+        for (side, val) in [
+            ("top", self.top),
+            ("bottom", self.bottom),
+            ("left", self.left),
+            ("right", self.right),
+        ] {
+            ensure!(
+                (0.0..1.0).contains(&val),
+                "{} margin {} must be in [0, 1), got {}",
+                name,
+                side,
+                val
+            );
+        }
+        ensure!(
+            self.left + self.right < 1.0,
+            "{} margins leave no horizontal space",
+            name
+        );
+        ensure!(
+            self.top + self.bottom < 1.0,
+            "{} margins leave no vertical space",
+            name
+        );
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub struct BBox {
     minx: i32,
     maxx: i32,
@@ -41,6 +83,10 @@ impl BBox {
         self.maxy - self.miny
     }
 
+    pub(crate) fn diagonal(&self) -> f32 {
+        (self.xrange() as f32).hypot(self.yrange() as f32)
+    }
+
     fn apply_margins(&self, margins: Margins) -> Self {
         let xrange = self.xrange() as f32;
         let yrange = self.yrange() as f32;
@@ -83,7 +129,7 @@ pub(crate) struct NumpadLayout {
     key_height: i32,
 }
 
-#[derive(Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
 pub(crate) enum SupportedLayout {
     UX433FA,
     M433IA,
@@ -93,6 +139,220 @@ pub(crate) enum SupportedLayout {
     G533,
 }
 
+/// Description of a numpad layout, independent of the touchpad's dimensions.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct LayoutSpec {
+    keys: Grid,
+    numpad: Margins,
+    /// `None` if the model doesn't have a numlock button.
+    numlock: Option<Margins>,
+    /// `None` if the model doesn't have a calc button.
+    calc: Option<Margins>,
+}
+
+impl LayoutSpec {
+    fn validate(&self) -> Result<()> {
+        ensure!(!self.keys.is_empty(), "Layout must have at least one row");
+        let ncols = self.keys[0].len();
+        ensure!(ncols != 0, "Layout must have at least one column");
+        ensure!(
+            self.keys.iter().all(|row| row.len() == ncols),
+            "All rows of the layout must have the same number of keys"
+        );
+        self.numpad.validate("numpad")?;
+        if let Some(numlock) = &self.numlock {
+            numlock.validate("numlock")?;
+        }
+        if let Some(calc) = &self.calc {
+            calc.validate("calc")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for SupportedLayout {
+    type Err = anyhow::Error;
+
+    /// Parse the name of the model, ignoring the case.
+    fn from_str(s: &str) -> Result<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|layout| format!("{:?}", layout).eq_ignore_ascii_case(s))
+            .ok_or_else(|| anyhow!("Unknown layout {:?}", s))
+    }
+}
+
+impl SupportedLayout {
+    pub(crate) const ALL: [Self; 6] = [
+        Self::UX433FA,
+        Self::M433IA,
+        Self::UX581,
+        Self::GX701,
+        Self::GX531,
+        Self::G533,
+    ];
+
+    /// The margins used for the numlock button on most models.
+    const NUMLOCK_MARGINS: Margins = Margins::new(0.0, 0.91, 0.95, 0.0);
+    /// The margins used for the calc button on most models.
+    const CALC_MARGINS: Margins = Margins::new(0.0, 0.91, 0.0, 0.95);
+
+    /// Get the spec of this preset.
+    pub(crate) fn spec(&self) -> LayoutSpec {
+        use EV_KEY::*;
+        use SupportedLayout::*;
+        match self {
+            UX433FA => LayoutSpec {
+                keys: vec![
+                    vec![KEY_KP7, KEY_KP8, KEY_KP9, KEY_KPSLASH, KEY_BACKSPACE],
+                    vec![KEY_KP4, KEY_KP5, KEY_KP6, KEY_KPASTERISK, KEY_BACKSPACE],
+                    vec![KEY_KP1, KEY_KP2, KEY_KP3, KEY_KPMINUS, KEY_KPENTER],
+                    vec![KEY_KP0, KEY_KP0, KEY_KPDOT, KEY_KPPLUS, KEY_KPENTER],
+                ],
+                numpad: Margins::new(0.1, 0.025, 0.05, 0.05),
+                numlock: Some(Self::NUMLOCK_MARGINS),
+                calc: Some(Self::CALC_MARGINS),
+            },
+            M433IA => LayoutSpec {
+                keys: vec![
+                    vec![KEY_KP7, KEY_KP8, KEY_KP9, KEY_KPSLASH, KEY_BACKSPACE],
+                    vec![KEY_KP4, KEY_KP5, KEY_KP6, KEY_KPASTERISK, KEY_BACKSPACE],
+                    vec![KEY_KP1, KEY_KP2, KEY_KP3, KEY_KPMINUS, KEY_5],
+                    vec![KEY_KP0, KEY_KPDOT, KEY_KPENTER, KEY_KPPLUS, KEY_KPEQUAL],
+                ],
+                numpad: Margins::new(0.1, 0.025, 0.05, 0.05),
+                numlock: Some(Self::NUMLOCK_MARGINS),
+                calc: Some(Self::CALC_MARGINS),
+            },
+            UX581 => LayoutSpec {
+                keys: vec![
+                    vec![KEY_KPEQUAL, KEY_5, KEY_BACKSPACE, KEY_BACKSPACE],
+                    vec![KEY_KP7, KEY_KP8, KEY_KP9, KEY_KPSLASH],
+                    vec![KEY_KP4, KEY_KP5, KEY_KP6, KEY_KPASTERISK],
+                    vec![KEY_KP1, KEY_KP2, KEY_KP3, KEY_KPMINUS],
+                    vec![KEY_KP0, KEY_KPDOT, KEY_KPENTER, KEY_KPPLUS],
+                ],
+                numpad: Margins::new(0.1, 0.025, 0.025, 0.025),
+                numlock: Some(Self::NUMLOCK_MARGINS),
+                calc: Some(Self::CALC_MARGINS),
+            },
+            GX701 => LayoutSpec {
+                keys: vec![
+                    vec![KEY_CALC, KEY_KPSLASH, KEY_KPASTERISK, KEY_KPMINUS],
+                    vec![KEY_KP7, KEY_KP8, KEY_KP9, KEY_KPPLUS],
+                    vec![KEY_KP4, KEY_KP5, KEY_KP6, KEY_KPPLUS],
+                    vec![KEY_KP1, KEY_KP2, KEY_KP3, KEY_KPENTER],
+                    vec![KEY_KP0, KEY_KP0, KEY_KPDOT, KEY_KPENTER],
+                ],
+                numpad: Margins::new(0.025, 0.025, 0.025, 0.025),
+                numlock: None,
+                calc: None,
+            },
+            GX531 => LayoutSpec {
+                keys: vec![
+                    vec![KEY_BACKSLASH, KEY_KPSLASH, KEY_KPASTERISK, KEY_KPMINUS],
+                    vec![KEY_KP7, KEY_KP8, KEY_KP9, KEY_KPPLUS],
+                    vec![KEY_KP4, KEY_KP5, KEY_KP6, KEY_KPPLUS],
+                    vec![KEY_KP1, KEY_KP2, KEY_KP3, KEY_KPENTER],
+                    vec![KEY_KP0, KEY_KP0, KEY_KPDOT, KEY_KPENTER],
+                ],
+                numpad: Margins::new(0.005, 0.005, 0.005, 0.005),
+                numlock: None,
+                calc: None,
+            },
+            G533 => LayoutSpec {
+                keys: vec![
+                    vec![KEY_KP7, KEY_KP8, KEY_KP9, KEY_KPSLASH, KEY_NUMLOCK],
+                    vec![KEY_KP4, KEY_KP5, KEY_KP6, KEY_KPASTERISK, KEY_BACKSPACE],
+                    vec![KEY_KP1, KEY_KP2, KEY_KP3, KEY_KPMINUS, KEY_KPENTER],
+                    vec![KEY_KP0, KEY_KP0, KEY_KPDOT, KEY_KPPLUS, KEY_KPENTER],
+                ],
+                numpad: Margins::new(0.0625, 0.0625, 0.075, 0.075),
+                numlock: Some(Margins::new(0.0625, 0.7, 0.73, 0.075)),
+                calc: None,
+            },
+        }
+    }
+}
+
+/// A layout specified in the config file.
+///
+/// Either the name of a preset, or a custom layout.
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum LayoutConfig {
+    Preset(SupportedLayout),
+    Custom(CustomLayout),
+}
+
+/// A user-defined layout.
+///
+/// Any field that is not specified is taken from the `extends` preset, or from the
+/// autodetected model if `extends` isn't given either.
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct CustomLayout {
+    extends: Option<SupportedLayout>,
+    keys: Option<Grid>,
+    numpad_margins: Option<Margins>,
+    numlock_margins: Option<Margins>,
+    calc_margins: Option<Margins>,
+}
+
+impl LayoutConfig {
+    /// The preset's name, or `custom`.
+    pub(crate) fn name(&self) -> String {
+        match self {
+            LayoutConfig::Preset(preset) => format!("{:?}", preset),
+            LayoutConfig::Custom(_) => "custom".to_owned(),
+        }
+    }
+
+    /// Whether this layout can make use of the autodetected model.
+    pub(crate) fn wants_detected(&self) -> bool {
+        matches!(
+            self,
+            LayoutConfig::Custom(CustomLayout { extends: None, .. })
+        )
+    }
+
+    /// Resolve the config into a complete, validated spec.
+    ///
+    /// `detected` is used as the base of custom layouts that don't specify `extends`.
+    pub(crate) fn spec(&self, detected: Option<SupportedLayout>) -> Result<LayoutSpec> {
+        let spec = match self {
+            LayoutConfig::Preset(preset) => preset.spec(),
+            LayoutConfig::Custom(custom) => {
+                let base = custom.extends.or(detected).map(|l| l.spec());
+                LayoutSpec {
+                    keys: custom
+                        .keys
+                        .clone()
+                        .or_else(|| base.as_ref().map(|b| b.keys.clone()))
+                        .ok_or_else(|| {
+                            anyhow!(
+                                "Custom layout needs either `keys` or `extends` \
+                                if the model can't be detected"
+                            )
+                        })?,
+                    numpad: custom
+                        .numpad_margins
+                        .or_else(|| base.as_ref().map(|b| b.numpad))
+                        .unwrap_or_default(),
+                    numlock: custom
+                        .numlock_margins
+                        .or_else(|| base.as_ref().and_then(|b| b.numlock)),
+                    calc: custom
+                        .calc_margins
+                        .or_else(|| base.as_ref().and_then(|b| b.calc)),
+                }
+            }
+        };
+        spec.validate()?;
+        Ok(spec)
+    }
+}
+
 impl NumpadLayout {
     /// Get a reference to the numpad layout's keys.
     pub fn keys(&self) -> &Grid {
@@ -118,8 +378,9 @@ impl NumpadLayout {
         if !bbox.contains(pos) {
             return None;
         }
-        let col = ((pos.x - bbox.minx) / self.key_width) as usize;
-        let row = ((pos.y - bbox.miny) / self.key_height) as usize;
+        // clamp to handle the points on the max edges of the bbox
+        let col = (((pos.x - bbox.minx) / self.key_width) as usize).min(self.keys[0].len() - 1);
+        let row = (((pos.y - bbox.miny) / self.key_height) as usize).min(self.keys.len() - 1);
         // Safety: We have already checked that bbox contains the point
         let key = unsafe { self.keys().get_unchecked(row).get_unchecked(col) };
         Some(*key)
@@ -137,196 +398,149 @@ impl NumpadLayout {
         self.calc_bbox.contains(pos)
     }
 
-    fn create(keys: Grid, numpad_bbox: BBox, numlock_bbox: BBox, calc_bbox: BBox) -> Self {
-        let key_width = numpad_bbox.xrange() / keys[0].len() as i32;
-        let key_height = numpad_bbox.yrange() / keys.len() as i32;
-        Self {
-            keys,
+    /// Create the layout from a spec, scaled to the touchpad's bbox.
+    ///
+    /// Fails if the keys would be less than a unit wide or high. `name` is for the error.
+    pub(crate) fn from_spec(name: &str, spec: LayoutSpec, bbox: BBox) -> Result<Self> {
+        let numpad_bbox = bbox.apply_margins(spec.numpad);
+        // if a bbox isn't present on this model, set it to values outside
+        // the actual touchpad bbox. this way, it will never be activated.
+        let numlock_bbox = spec
+            .numlock
+            .map_or_else(|| bbox.disjoint_dummy(), |m| bbox.apply_margins(m));
+        let calc_bbox = spec
+            .calc
+            .map_or_else(|| bbox.disjoint_dummy(), |m| bbox.apply_margins(m));
+        let (ncols, nrows) = (spec.keys[0].len() as i32, spec.keys.len() as i32);
+        let key_width = numpad_bbox.xrange() / ncols;
+        let key_height = numpad_bbox.yrange() / nrows;
+        ensure!(
+            key_width >= 1 && key_height >= 1,
+            "The {} layout doesn't fit on the touchpad: its {}x{} keys don't fit in a \
+            numpad area of {}x{} units",
+            name,
+            ncols,
+            nrows,
+            numpad_bbox.xrange(),
+            numpad_bbox.yrange()
+        );
+        Ok(Self {
+            keys: spec.keys,
             numpad_bbox,
             numlock_bbox,
             calc_bbox,
             key_width,
             key_height,
-        }
+        })
     }
 
-    pub fn ux433fa(bbox: BBox) -> Self {
-        use EV_KEY::*;
-        Self::create(
-            vec![
-                vec![KEY_KP7, KEY_KP8, KEY_KP9, KEY_KPSLASH, KEY_BACKSPACE],
-                vec![KEY_KP4, KEY_KP5, KEY_KP6, KEY_KPASTERISK, KEY_BACKSPACE],
-                vec![KEY_KP1, KEY_KP2, KEY_KP3, KEY_KPMINUS, KEY_KPENTER],
-                vec![KEY_KP0, KEY_KP0, KEY_KPDOT, KEY_KPPLUS, KEY_KPENTER],
-            ],
-            bbox.apply_margins(Margins {
-                top: 0.1,
-                bottom: 0.025,
-                left: 0.05,
-                right: 0.05,
-            }),
-            bbox.apply_margins(Margins {
-                top: 0.0,
-                bottom: 0.91,
-                left: 0.95,
-                right: 0.0,
-            }),
-            bbox.apply_margins(Margins {
-                top: 0.0,
-                bottom: 0.91,
-                left: 0.0,
-                right: 0.95,
-            }),
-        )
+    pub(crate) fn from_config(
+        layout: &LayoutConfig,
+        detected: Option<SupportedLayout>,
+        bbox: BBox,
+    ) -> Result<Self> {
+        Self::from_spec(&layout.name(), layout.spec(detected)?, bbox)
     }
+}
 
-    pub fn m433ia(bbox: BBox) -> Self {
-        use EV_KEY::*;
-        Self::create(
-            vec![
-                vec![KEY_KP7, KEY_KP8, KEY_KP9, KEY_KPSLASH, KEY_BACKSPACE],
-                vec![KEY_KP4, KEY_KP5, KEY_KP6, KEY_KPASTERISK, KEY_BACKSPACE],
-                vec![KEY_KP1, KEY_KP2, KEY_KP3, KEY_KPMINUS, KEY_5],
-                vec![KEY_KP0, KEY_KPDOT, KEY_KPENTER, KEY_KPPLUS, KEY_KPEQUAL],
-            ],
-            bbox.apply_margins(Margins {
-                top: 0.1,
-                bottom: 0.025,
-                left: 0.05,
-                right: 0.05,
-            }),
-            bbox.apply_margins(Margins {
-                top: 0.0,
-                bottom: 0.91,
-                left: 0.95,
-                right: 0.0,
-            }),
-            bbox.apply_margins(Margins {
-                top: 0.0,
-                bottom: 0.91,
-                left: 0.0,
-                right: 0.95,
-            }),
-        )
-    }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    pub fn ux581(bbox: BBox) -> Self {
-        use EV_KEY::*;
-        Self::create(
-            vec![
-                vec![KEY_KPEQUAL, KEY_5, KEY_BACKSPACE, KEY_BACKSPACE],
-                vec![KEY_KP7, KEY_KP8, KEY_KP9, KEY_KPSLASH],
-                vec![KEY_KP4, KEY_KP5, KEY_KP6, KEY_KPASTERISK],
-                vec![KEY_KP1, KEY_KP2, KEY_KP3, KEY_KPMINUS],
-                vec![KEY_KP0, KEY_KPDOT, KEY_KPENTER, KEY_KPPLUS],
-            ],
-            bbox.apply_margins(Margins {
-                top: 0.1,
-                bottom: 0.025,
-                left: 0.025,
-                right: 0.025,
-            }),
-            bbox.apply_margins(Margins {
-                top: 0.0,
-                bottom: 0.91,
-                left: 0.95,
-                right: 0.0,
-            }),
-            bbox.apply_margins(Margins {
-                top: 0.0,
-                bottom: 0.91,
-                left: 0.0,
-                right: 0.95,
-            }),
-        )
+    #[test]
+    fn test_parse_layout() {
+        assert_eq!(
+            "UX433FA".parse::<SupportedLayout>().unwrap(),
+            SupportedLayout::UX433FA
+        );
+        assert_eq!(
+            "gx701".parse::<SupportedLayout>().unwrap(),
+            SupportedLayout::GX701
+        );
+        assert!("UX9000".parse::<SupportedLayout>().is_err());
     }
 
-    pub fn gx701(bbox: BBox) -> Self {
-        use EV_KEY::*;
-        Self::create(
-            vec![
-                vec![KEY_CALC, KEY_KPSLASH, KEY_KPASTERISK, KEY_KPMINUS],
-                vec![KEY_KP7, KEY_KP8, KEY_KP9, KEY_KPPLUS],
-                vec![KEY_KP4, KEY_KP5, KEY_KP6, KEY_KPPLUS],
-                vec![KEY_KP1, KEY_KP2, KEY_KP3, KEY_KPENTER],
-                vec![KEY_KP0, KEY_KP0, KEY_KPDOT, KEY_KPENTER],
-            ],
-            bbox.apply_margins(Margins {
-                top: 0.025,
-                bottom: 0.025,
-                left: 0.025,
-                right: 0.025,
-            }),
-            // these bboxes aren't present on this model.
-            // set to values outside the actual touchpad bbox.
-            // this way, they will never be activated.
-            bbox.disjoint_dummy(),
-            bbox.disjoint_dummy(),
-        )
+    #[test]
+    fn test_custom_layout_extends_preset() {
+        #[derive(Deserialize)]
+        struct Wrapper {
+            layout: LayoutConfig,
+        }
+        let wrapper: Wrapper =
+            toml::from_str(r#"layout = { extends = "UX433FA", numpad_margins = { top = 0.2 } }"#)
+                .unwrap();
+        let spec = wrapper.layout.spec(None).unwrap();
+        let base = SupportedLayout::UX433FA.spec();
+        assert_eq!(spec.keys, base.keys);
+        assert_eq!(spec.numpad, Margins::new(0.2, 0.0, 0.0, 0.0));
+        assert_eq!(spec.numlock, base.numlock);
+        assert_eq!(spec.calc, base.calc);
     }
 
-    pub fn gx531(bbox: BBox) -> Self {
+    #[test]
+    fn test_custom_layout_validation() {
         use EV_KEY::*;
-        Self::create(
-            vec![
-                vec![KEY_BACKSLASH, KEY_KPSLASH, KEY_KPASTERISK, KEY_KPMINUS],
-                vec![KEY_KP7, KEY_KP8, KEY_KP9, KEY_KPPLUS],
-                vec![KEY_KP4, KEY_KP5, KEY_KP6, KEY_KPPLUS],
-                vec![KEY_KP1, KEY_KP2, KEY_KP3, KEY_KPENTER],
-                vec![KEY_KP0, KEY_KP0, KEY_KPDOT, KEY_KPENTER],
-            ],
-            bbox.apply_margins(Margins {
-                top: 0.005,
-                bottom: 0.005,
-                left: 0.005,
-                right: 0.005,
-            }),
-            // these bboxes aren't present on this model.
-            // set to values outside the actual touchpad bbox.
-            // this way, they will never be activated.
-            bbox.disjoint_dummy(),
-            bbox.disjoint_dummy(),
-        )
+        let jagged = LayoutConfig::Custom(CustomLayout {
+            keys: Some(vec![vec![KEY_KP1, KEY_KP2], vec![KEY_KP3]]),
+            ..Default::default()
+        });
+        assert!(jagged.spec(None).is_err());
+
+        let no_keys = LayoutConfig::Custom(CustomLayout::default());
+        assert!(no_keys.spec(None).is_err());
+        assert_eq!(
+            no_keys.spec(Some(SupportedLayout::G533)).unwrap(),
+            SupportedLayout::G533.spec()
+        );
+
+        let bad_margins = LayoutConfig::Custom(CustomLayout {
+            extends: Some(SupportedLayout::GX701),
+            numpad_margins: Some(Margins::new(0.0, 0.0, 0.6, 0.5)),
+            ..Default::default()
+        });
+        assert!(bad_margins.spec(None).is_err());
     }
 
-    pub fn g533(bbox: BBox) -> Self {
-        use EV_KEY::*;
-        Self::create(
-            vec![
-                vec![KEY_KP7, KEY_KP8, KEY_KP9, KEY_KPSLASH, KEY_NUMLOCK],
-                vec![KEY_KP4, KEY_KP5, KEY_KP6, KEY_KPASTERISK, KEY_BACKSPACE],
-                vec![KEY_KP1, KEY_KP2, KEY_KP3, KEY_KPMINUS, KEY_KPENTER],
-                vec![KEY_KP0, KEY_KP0, KEY_KPDOT, KEY_KPPLUS, KEY_KPENTER],
-            ],
-            bbox.apply_margins(Margins {
-                top: 0.0625,
-                bottom: 0.0625,
-                left: 0.075,
-                right: 0.075,
-            }),
-            bbox.apply_margins(Margins {
-                top: 0.0625,
-                bottom: 0.7,
-                left: 0.73,
-                right: 0.075,
-            }),
-            // this bbox isn't present on this model.
-            // set to values outside the actual touchpad bbox.
-            // this way, they will never be activated.
-            bbox.disjoint_dummy(),
+    #[test]
+    fn test_get_key() {
+        let layout = NumpadLayout::from_config(
+            &LayoutConfig::Preset(SupportedLayout::GX531),
+            None,
+            BBox::new(0, 1000, 0, 500),
         )
+        .unwrap();
+        assert_eq!(
+            layout.get_key(Point { x: 5, y: 5 }),
+            Some(EV_KEY::KEY_BACKSLASH)
+        );
+        assert_eq!(
+            layout.get_key(Point { x: 995, y: 495 }),
+            Some(EV_KEY::KEY_KPENTER)
+        );
+        assert_eq!(layout.get_key(Point { x: 0, y: 0 }), None);
+        assert!(!layout.in_numlock_bbox(Point { x: 1000, y: 0 }));
     }
 
-    pub(crate) fn from_supported_layout(layout: &SupportedLayout, bbox: BBox) -> Result<Self> {
-        use SupportedLayout::*;
-        let layout = match layout {
-            UX433FA => Self::ux433fa(bbox),
-            M433IA => Self::m433ia(bbox),
-            UX581 => Self::ux581(bbox),
-            GX701 => Self::gx701(bbox),
-            GX531 => Self::gx531(bbox),
-            G533 => Self::g533(bbox),
-        };
-        Ok(layout)
+    #[test]
+    fn test_layout_too_small() {
+        // valid margins, but they leave less than a unit for each of the 5 columns
+        let narrow = LayoutConfig::Custom(CustomLayout {
+            extends: Some(SupportedLayout::UX433FA),
+            numpad_margins: Some(Margins::new(0.0, 0.0, 0.45, 0.5499)),
+            ..Default::default()
+        });
+        let err =
+            NumpadLayout::from_config(&narrow, None, BBox::new(0, 3000, 0, 2000)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The custom layout doesn't fit on the touchpad: its 5x4 keys don't fit in a \
+            numpad area of 1x2000 units"
+        );
+
+        let tiny = BBox::new(0, 3, 0, 3);
+        let preset = LayoutConfig::Preset(SupportedLayout::UX433FA);
+        assert!(NumpadLayout::from_config(&preset, None, tiny).is_err());
+        let layout = NumpadLayout::from_config(&preset, None, BBox::new(0, 100, 0, 100)).unwrap();
+        assert!(layout.get_key(Point { x: 50, y: 50 }).is_some());
     }
 }
@@ -0,0 +1,153 @@
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+/// A limit on one of the touch size/pressure axes.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub(crate) enum Threshold {
+    /// Raw value of the axis, as reported by evdev
+    Units(i32),
+    /// Fraction of the axis' maximum
+    Fraction(f32),
+}
+
+/// Palm rejection settings, as given in the config file.
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct PalmConfig {
+    /// Trust the touchpad's own palm detection (`ABS_MT_TOOL_TYPE` of `MT_TOOL_PALM`)
+    use_tool_type: bool,
+    /// Contacts larger than this along `ABS_MT_TOUCH_MAJOR` are palms
+    touch_major: Option<Threshold>,
+    /// Contacts larger than this along `ABS_MT_WIDTH_MAJOR` are palms
+    width_major: Option<Threshold>,
+    /// Contacts pressing harder than this (`ABS_MT_PRESSURE`) are palms
+    pressure: Option<Threshold>,
+}
+
+impl Default for PalmConfig {
+    fn default() -> Self {
+        Self {
+            use_tool_type: true,
+            touch_major: None,
+            width_major: None,
+            pressure: None,
+        }
+    }
+}
+
+/// Maximum values of the palm related axes, or `None` if the touchpad doesn't have them.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct PalmAxes {
+    pub(crate) touch_major: Option<i32>,
+    pub(crate) width_major: Option<i32>,
+    pub(crate) pressure: Option<i32>,
+}
+
+/// Size and pressure of a contact, for the axes that the touchpad has.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ContactSize {
+    pub(crate) touch_major: i32,
+    pub(crate) width_major: i32,
+    pub(crate) pressure: i32,
+}
+
+/// Palm rejection thresholds, resolved for a specific touchpad.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct PalmThresholds {
+    pub(crate) use_tool_type: bool,
+    touch_major: Option<i32>,
+    width_major: Option<i32>,
+    pressure: Option<i32>,
+}
+
+impl PalmThresholds {
+    pub(crate) fn resolve(config: &PalmConfig, axes: &PalmAxes) -> Self {
+        let resolve = |name: &str, threshold: Option<Threshold>, max: Option<i32>| {
+            let threshold = threshold?;
+            let Some(max) = max else {
+                warn!(
+                    "Touchpad doesn't report {}, ignoring its palm threshold",
+                    name
+                );
+                return None;
+            };
+            Some(match threshold {
+                Threshold::Units(units) => units,
+                Threshold::Fraction(fraction) => (fraction * max as f32).round() as i32,
+            })
+        };
+        Self {
+            use_tool_type: config.use_tool_type,
+            touch_major: resolve("ABS_MT_TOUCH_MAJOR", config.touch_major, axes.touch_major),
+            width_major: resolve("ABS_MT_WIDTH_MAJOR", config.width_major, axes.width_major),
+            pressure: resolve("ABS_MT_PRESSURE", config.pressure, axes.pressure),
+        }
+    }
+
+    /// Check whether a contact of the given size is too big to be a finger.
+    pub(crate) fn exceeded_by(&self, size: &ContactSize) -> bool {
+        let exceeds = |threshold: Option<i32>, value| threshold.is_some_and(|t| value > t);
+        exceeds(self.touch_major, size.touch_major)
+            || exceeds(self.width_major, size.width_major)
+            || exceeds(self.pressure, size.pressure)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let config: PalmConfig = toml::from_str(
+            r#"
+            touch_major = { fraction = 0.5 }
+            width_major = { units = 20 }
+            pressure = { units = 130 }
+            "#,
+        )
+        .unwrap();
+        let axes = PalmAxes {
+            touch_major: Some(255),
+            width_major: Some(255),
+            // not reported by this touchpad
+            pressure: None,
+        };
+        let thresholds = PalmThresholds::resolve(&config, &axes);
+        assert!(thresholds.use_tool_type);
+
+        let finger = ContactSize {
+            touch_major: 100,
+            width_major: 20,
+            pressure: 200,
+        };
+        assert!(!thresholds.exceeded_by(&finger));
+        let long = ContactSize {
+            touch_major: 129,
+            ..finger
+        };
+        assert!(thresholds.exceeded_by(&long));
+        let wide = ContactSize {
+            width_major: 21,
+            ..finger
+        };
+        assert!(thresholds.exceeded_by(&wide));
+    }
+
+    #[test]
+    fn test_disabled_by_default() {
+        let axes = PalmAxes {
+            touch_major: Some(255),
+            width_major: Some(255),
+            pressure: Some(255),
+        };
+        let thresholds = PalmThresholds::resolve(&PalmConfig::default(), &axes);
+        let huge = ContactSize {
+            touch_major: 255,
+            width_major: 255,
+            pressure: 255,
+        };
+        assert!(!thresholds.exceeded_by(&huge));
+    }
+}
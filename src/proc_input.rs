@@ -0,0 +1,367 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Context, Result, anyhow};
+use evdev_rs::enums::{EV_ABS, EV_KEY, EV_LED, EventType};
+
+/// Bus type of the legacy i8042 controller, used by the internal keyboard.
+pub(crate) const BUS_I8042: u16 = 0x11;
+
+/// A capability bitmap, as printed by the kernel in the `B:` lines.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct Bitmap {
+    /// Words of the bitmap, least significant first.
+    words: Vec<u64>,
+}
+
+impl Bitmap {
+    /// Parse a bitmap printed as space-separated hex words, most significant first.
+    fn parse(s: &str) -> Result<Self> {
+        let words = s
+            .split_whitespace()
+            .rev()
+            .map(|word| u64::from_str_radix(word, 16))
+            .collect::<Result<_, _>>()
+            .with_context(|| format!("Invalid bitmap {:?}", s))?;
+        Ok(Self { words })
+    }
+
+    pub(crate) fn has(&self, bit: usize) -> bool {
+        self.words
+            .get(bit / 64)
+            .is_some_and(|word| word & (1 << (bit % 64)) != 0)
+    }
+}
+
+/// Find the N of the innermost `i2c-N` bus in a sysfs path.
+pub(crate) fn i2c_bus_from_path(path: &str) -> Option<u32> {
+    path.rsplit('/')
+        .find_map(|seg| seg.strip_prefix("i2c-").and_then(|id| id.parse().ok()))
+}
+
+/// A single device entry of `/proc/bus/input/devices`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct InputDeviceInfo {
+    pub(crate) bus: u16,
+    pub(crate) vendor: u16,
+    pub(crate) product: u16,
+    pub(crate) version: u16,
+    pub(crate) name: String,
+    pub(crate) phys: String,
+    pub(crate) sysfs: String,
+    pub(crate) uniq: String,
+    pub(crate) handlers: Vec<String>,
+    /// Capability bitmaps, keyed by their type (`EV`, `KEY`, `ABS`, `LED`, ...).
+    pub(crate) capabilities: BTreeMap<String, Bitmap>,
+}
+
+impl InputDeviceInfo {
+    /// The N in the `/dev/input/eventN` handler of this device.
+    pub(crate) fn event_id(&self) -> Option<u32> {
+        self.handlers
+            .iter()
+            .find_map(|h| h.strip_prefix("event").and_then(|id| id.parse().ok()))
+    }
+
+    /// The N in the `i2c-N` bus that the device is connected to.
+    pub(crate) fn i2c_id(&self) -> Option<u32> {
+        i2c_bus_from_path(&self.sysfs)
+    }
+
+    fn has(&self, kind: &str, bit: usize) -> bool {
+        self.capabilities
+            .get(kind)
+            .is_some_and(|bitmap| bitmap.has(bit))
+    }
+
+    pub(crate) fn has_event_type(&self, ev_type: EventType) -> bool {
+        self.has("EV", ev_type as usize)
+    }
+
+    pub(crate) fn has_key(&self, key: EV_KEY) -> bool {
+        self.has_event_type(EventType::EV_KEY) && self.has("KEY", key as usize)
+    }
+
+    pub(crate) fn has_abs(&self, abs: EV_ABS) -> bool {
+        self.has_event_type(EventType::EV_ABS) && self.has("ABS", abs as usize)
+    }
+
+    pub(crate) fn has_led(&self, led: EV_LED) -> bool {
+        self.has_event_type(EventType::EV_LED) && self.has("LED", led as usize)
+    }
+
+    /// Whether this is the keyboard built into the laptop, as opposed to an external one.
+    pub(crate) fn is_internal_keyboard(&self) -> bool {
+        is_internal_keyboard(self.bus, &self.phys)
+    }
+}
+
+/// Whether the keyboard with the given bus type and physical path is built into the laptop.
+pub(crate) fn is_internal_keyboard(bus: u16, phys: &str) -> bool {
+    bus == BUS_I8042 || phys.starts_with("isa0060/")
+}
+
+/// Parse the `I:` line, of the form `Bus=0018 Vendor=04f3 Product=3087 Version=0100`.
+fn parse_id_line(dev: &mut InputDeviceInfo, line: &str) -> Result<()> {
+    for field in line.split_whitespace() {
+        let (key, val) = field
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Invalid field {:?}", field))?;
+        let val = u16::from_str_radix(val, 16).with_context(|| format!("Invalid {}", key))?;
+        match key {
+            "Bus" => dev.bus = val,
+            "Vendor" => dev.vendor = val,
+            "Product" => dev.product = val,
+            "Version" => dev.version = val,
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+fn parse_line(dev: &mut InputDeviceInfo, line: &str) -> Result<()> {
+    let Some((kind, rest)) = line.split_once(": ") else {
+        // some fields (eg. `U: Uniq=`) can be empty
+        return Ok(());
+    };
+    let value = |key: &str| {
+        rest.strip_prefix(key)
+            .and_then(|r| r.strip_prefix('='))
+            .map(|v| v.trim_matches('"').to_owned())
+            .ok_or_else(|| anyhow!("Expected {}= in {:?}", key, line))
+    };
+    match kind {
+        "I" => parse_id_line(dev, rest)?,
+        "N" => dev.name = value("Name")?,
+        "P" => dev.phys = value("Phys")?,
+        "S" => dev.sysfs = value("Sysfs")?,
+        "U" => dev.uniq = value("Uniq")?,
+        "H" => {
+            dev.handlers = value("Handlers")?
+                .split_whitespace()
+                .map(str::to_owned)
+                .collect()
+        }
+        "B" => {
+            let (cap, bitmap) = rest
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Invalid bitmap line {:?}", line))?;
+            dev.capabilities
+                .insert(cap.to_owned(), Bitmap::parse(bitmap)?);
+        }
+        // ignore anything added by newer kernels
+        _ => (),
+    }
+    Ok(())
+}
+
+/// Parse the contents of `/proc/bus/input/devices`.
+pub(crate) fn parse_proc_input(data: &str) -> Result<Vec<InputDeviceInfo>> {
+    let mut devices = Vec::new();
+    let mut cur: Option<InputDeviceInfo> = None;
+    for (lineno, line) in data.lines().enumerate() {
+        let line = line.trim_end();
+        if line.is_empty() {
+            // end of device info
+            devices.extend(cur.take());
+            continue;
+        }
+        let dev = cur.get_or_insert_with(Default::default);
+        parse_line(dev, line).with_context(|| format!("Error on line {}", lineno + 1))?;
+    }
+    devices.extend(cur);
+    Ok(devices)
+}
+
+/// How likely a device is to be the numpad touchpad. Higher is better.
+///
+/// Rules, in order:
+/// 1. It must be named "... Touchpad", have an evdev handler, sit on an I2C bus and
+///    report multitouch positions. Otherwise it is not a candidate at all.
+/// 2. Asus' own touchpads (`ASUE`, `ASUP`, `ASCP`) are preferred over `ELAN` ones.
+///    Touchpads from other vendors are not candidates.
+fn touchpad_rank(dev: &InputDeviceInfo) -> Option<u8> {
+    if !dev.name.contains("Touchpad")
+        || dev.event_id().is_none()
+        || dev.i2c_id().is_none()
+        || !dev.has_abs(EV_ABS::ABS_MT_POSITION_X)
+    {
+        return None;
+    }
+    if ["ASUE", "ASUP", "ASCP"]
+        .iter()
+        .any(|prefix| dev.name.starts_with(prefix))
+    {
+        Some(2)
+    } else if dev.name.starts_with("ELAN") {
+        Some(1)
+    } else {
+        None
+    }
+}
+
+/// How likely a device is to be the laptop's keyboard. Higher is better.
+///
+/// Rules, in order:
+/// 1. It must be the `AT Translated Set 2 keyboard` or an Asus keyboard, have an evdev
+///    handler and support `KEY_NUMLOCK`. Otherwise it is not a candidate at all.
+/// 2. Keyboards that have a numlock LED are preferred.
+/// 3. The internal keyboard is preferred over external ones.
+fn keyboard_rank(dev: &InputDeviceInfo) -> Option<(bool, bool)> {
+    let name_matches = dev.name.starts_with("AT Translated Set 2 keyboard")
+        || ((dev.name.starts_with("ASUE") || dev.name.starts_with("Asus"))
+            && dev.name.contains("Keyboard"));
+    if !name_matches || dev.event_id().is_none() || !dev.has_key(EV_KEY::KEY_NUMLOCK) {
+        return None;
+    }
+    Some((dev.has_led(EV_LED::LED_NUML), dev.is_internal_keyboard()))
+}
+
+/// Sort the devices that pass `rank` from best to worst.
+///
+/// Devices with the same rank keep the order they appear in.
+fn ranked<R: Ord>(
+    devices: &[InputDeviceInfo],
+    rank: impl Fn(&InputDeviceInfo) -> Option<R>,
+) -> Vec<&InputDeviceInfo> {
+    let mut candidates: Vec<_> = devices
+        .iter()
+        .filter_map(|dev| rank(dev).map(|r| (r, dev)))
+        .collect();
+    candidates.sort_by(|(a, _), (b, _)| b.cmp(a));
+    candidates.into_iter().map(|(_, dev)| dev).collect()
+}
+
+/// All the possible touchpads, best first.
+pub(crate) fn touchpad_candidates(devices: &[InputDeviceInfo]) -> Vec<&InputDeviceInfo> {
+    ranked(devices, touchpad_rank)
+}
+
+/// All the possible keyboards, best first.
+pub(crate) fn keyboard_candidates(devices: &[InputDeviceInfo]) -> Vec<&InputDeviceInfo> {
+    ranked(devices, keyboard_rank)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    fn fixture(name: &str) -> Vec<InputDeviceInfo> {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/proc_input")
+            .join(name);
+        parse_proc_input(&std::fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    /// Returns the names of the best touchpad and keyboard
+    fn detect(name: &str) -> (String, String) {
+        let devices = fixture(name);
+        (
+            touchpad_candidates(&devices)[0].name.clone(),
+            keyboard_candidates(&devices)[0].name.clone(),
+        )
+    }
+
+    #[test]
+    fn test_parse() {
+        let devices = fixture("ux433fa.txt");
+        let touchpad = devices
+            .iter()
+            .find(|d| d.name == "ELAN1200:00 04F3:3087 Touchpad")
+            .unwrap();
+        assert_eq!(touchpad.bus, 0x18);
+        assert_eq!(touchpad.vendor, 0x04f3);
+        assert_eq!(touchpad.product, 0x3087);
+        assert_eq!(touchpad.version, 0x0100);
+        assert_eq!(touchpad.phys, "i2c-ELAN1200:00");
+        assert_eq!(touchpad.handlers, ["mouse0", "event8"]);
+        assert_eq!(touchpad.event_id(), Some(8));
+        assert_eq!(touchpad.i2c_id(), Some(1));
+        assert!(touchpad.has_abs(EV_ABS::ABS_MT_POSITION_X));
+        assert!(!touchpad.has_abs(EV_ABS::ABS_MT_PRESSURE));
+        assert!(touchpad.has_key(EV_KEY::BTN_TOOL_FINGER));
+    }
+
+    #[test]
+    fn test_internal_keyboard() {
+        let devices = fixture("gx701.txt");
+        let internal = |name: &str| {
+            devices
+                .iter()
+                .find(|d| d.name == name)
+                .unwrap()
+                .is_internal_keyboard()
+        };
+        assert!(internal("AT Translated Set 2 keyboard"));
+        assert!(!internal("Asus Keyboard"));
+        // the bus type alone is enough, eg. for an evdev without a physical path
+        assert!(is_internal_keyboard(BUS_I8042, ""));
+        assert!(!is_internal_keyboard(0x03, "usb-0000:00:14.0-2/input0"));
+    }
+
+    #[test]
+    fn test_bitmap() {
+        let bitmap = Bitmap::parse("1 0 8000000000000001").unwrap();
+        assert!(bitmap.has(0));
+        assert!(bitmap.has(63));
+        assert!(!bitmap.has(64));
+        assert!(bitmap.has(128));
+        assert!(!bitmap.has(1000));
+        assert!(Bitmap::parse("xyz").is_err());
+    }
+
+    #[test]
+    fn test_detect_ux433fa() {
+        assert_eq!(
+            detect("ux433fa.txt"),
+            (
+                "ELAN1200:00 04F3:3087 Touchpad".to_owned(),
+                "AT Translated Set 2 keyboard".to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn test_detect_um433ia() {
+        // the ELAN touchscreen must not be picked up
+        assert_eq!(
+            detect("um433ia.txt"),
+            (
+                "ASUE1200:00 04F3:3087 Touchpad".to_owned(),
+                "AT Translated Set 2 keyboard".to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn test_detect_asus_touchpad_preferred() {
+        // ELAN touchpad is listed first, but ASUP should win
+        let devices = fixture("ux581.txt");
+        let touchpads = touchpad_candidates(&devices);
+        assert_eq!(touchpads[0].name, "ASUP1205:00 093A:2003 Touchpad");
+        assert_eq!(touchpads[0].i2c_id(), Some(0));
+        assert_eq!(touchpads[1].name, "ELAN1300:00 04F3:3104 Touchpad");
+    }
+
+    #[test]
+    fn test_detect_gx701() {
+        // the AT keyboard doesn't have a numlock LED here, the USB keyboard does
+        let devices = fixture("gx701.txt");
+        let keyboards = keyboard_candidates(&devices);
+        assert_eq!(keyboards[0].name, "Asus Keyboard");
+        assert_eq!(keyboards[0].event_id(), Some(4));
+        assert_eq!(keyboards[1].name, "AT Translated Set 2 keyboard");
+        assert_eq!(
+            touchpad_candidates(&devices)[0].name,
+            "ASUE1406:00 04F3:3101 Touchpad"
+        );
+    }
+
+    #[test]
+    fn test_detect_none() {
+        let devices = parse_proc_input("").unwrap();
+        assert!(devices.is_empty());
+        assert!(touchpad_candidates(&devices).is_empty());
+    }
+}
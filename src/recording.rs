@@ -0,0 +1,452 @@
+use std::cell::Cell;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, LineWriter, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use evdev_rs::enums::{EV_LED, EventCode};
+use evdev_rs::{InputEvent, TimeVal};
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::control::Request;
+use crate::devices::TouchpadInfo;
+use crate::engine::{Action, Engine, counts_as_typing};
+use crate::numpad_layout::{LayoutConfig, SupportedLayout};
+use crate::state_file::SavedState;
+use crate::util::{Clock, add_duration};
+
+/// The first line of a recording.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Header {
+    /// Contents of the config file, so that the replay behaves the same
+    pub(crate) config: String,
+    pub(crate) touchpad: TouchpadInfo,
+    /// When the recording started
+    pub(crate) started_at: TimeVal,
+}
+
+/// An input to the numpad logic. A recording has one of these on each line after the header.
+///
+/// Devices disconnecting and coming back aren't recorded.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Record {
+    /// State restored from the last run, right before `InitNumlock`
+    SavedState(SavedState),
+    /// State of the numlock LED at startup
+    InitNumlock(i32),
+    Touchpad(InputEvent),
+    /// A change of the numlock LED
+    Keyboard {
+        /// Whether it is built into the laptop
+        internal: bool,
+        event: InputEvent,
+    },
+    /// A key that counts as typing was pressed on the laptop's keyboard. Which one isn't kept.
+    Typing(TimeVal),
+    /// A request from a control client, or over D-Bus
+    Request {
+        time: TimeVal,
+        request: Request,
+    },
+    /// The config was reloaded, with these contents
+    Config {
+        time: TimeVal,
+        config: String,
+    },
+}
+
+impl Record {
+    /// What to record of a keyboard event, if anything.
+    ///
+    /// Only what the numpad logic looks at is kept, so that the recording doesn't have
+    /// what was typed meanwhile.
+    pub(crate) fn keyboard(event: &InputEvent, internal: bool) -> Option<Self> {
+        match event.event_code {
+            EventCode::EV_LED(EV_LED::LED_NUML) => Some(Self::Keyboard {
+                internal,
+                event: event.clone(),
+            }),
+            EventCode::EV_KEY(key) if event.value != 0 && internal && counts_as_typing(key) => {
+                Some(Self::Typing(event.time))
+            }
+            _ => None,
+        }
+    }
+
+    fn time(&self) -> Option<TimeVal> {
+        match self {
+            Self::SavedState(_) | Self::InitNumlock(_) => None,
+            Self::Touchpad(event) | Self::Keyboard { event, .. } => Some(event.time),
+            Self::Typing(time) | Self::Request { time, .. } | Self::Config { time, .. } => {
+                Some(*time)
+            }
+        }
+    }
+}
+
+/// Writes the inputs to a file, as JSON lines.
+pub(crate) struct Recorder {
+    // flushed on every line, so that nothing is lost when the daemon is killed
+    writer: LineWriter<File>,
+}
+
+impl std::fmt::Debug for Recorder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Recorder")
+    }
+}
+
+impl Recorder {
+    /// Start a recording at `path`. Only its owner may read it.
+    pub(crate) fn create(path: &Path, header: &Header) -> Result<Self> {
+        let create = || -> std::io::Result<File> {
+            let file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .mode(0o600)
+                .open(path)?;
+            // the mode is only used for new files
+            file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+            Ok(file)
+        };
+        let file =
+            create().with_context(|| format!("Could not create recording {}", path.display()))?;
+        let mut recorder = Self {
+            writer: LineWriter::new(file),
+        };
+        recorder.write_line(header)?;
+        Ok(recorder)
+    }
+
+    fn write_line(&mut self, value: &impl Serialize) -> Result<()> {
+        serde_json::to_writer(&mut self.writer, value)?;
+        self.writer.write_all(b"\n")?;
+        Ok(())
+    }
+
+    pub(crate) fn record(&mut self, record: &Record) -> Result<()> {
+        self.write_line(record)
+            .context("Could not write to recording")
+    }
+}
+
+/// Follows the timestamps of the recorded events.
+#[derive(Debug)]
+pub(crate) struct ReplayClock {
+    now: Cell<TimeVal>,
+}
+
+impl ReplayClock {
+    fn new(start: TimeVal) -> Self {
+        Self {
+            now: Cell::new(start),
+        }
+    }
+
+    /// Move to `time`. Never goes back, since the events of different devices may be
+    /// slightly out of order.
+    fn set(&self, time: TimeVal) {
+        self.now.set(self.now.get().max(time));
+    }
+}
+
+impl Clock for ReplayClock {
+    fn now(&self) -> TimeVal {
+        self.now.get()
+    }
+}
+
+/// Run the timers of the engine that run out before `time`.
+fn run_timers_until(
+    engine: &mut Engine<ReplayClock>,
+    time: TimeVal,
+    actions: &mut Vec<(TimeVal, Action)>,
+) {
+    while let Some(remaining) = engine.next_timeout() {
+        let deadline = add_duration(engine.clock().now(), remaining);
+        if deadline > time {
+            break;
+        }
+        engine.clock().set(deadline);
+        actions.extend(engine.handle_timeouts().into_iter().map(|a| (deadline, a)));
+    }
+}
+
+/// Feed a recording through the numpad logic, and return the actions it took, with their time.
+///
+/// No devices are needed. Timers still in progress when the recording ends are ignored.
+/// `layout` overrides the one in the recorded config.
+pub(crate) fn replay(
+    reader: impl BufRead,
+    layout: Option<SupportedLayout>,
+) -> Result<Vec<(TimeVal, Action)>> {
+    let parse_config = |text: &str| -> Result<Config> {
+        let mut config: Config = toml::from_str(text).context("Invalid config in recording")?;
+        if let Some(layout) = layout {
+            config.set_layout(LayoutConfig::Preset(layout));
+        }
+        Ok(config)
+    };
+    let mut lines = reader.lines();
+    let header = lines.next().context("Recording is empty")??;
+    let header: Header = serde_json::from_str(&header).context("Invalid recording header")?;
+    let config = parse_config(&header.config)?;
+    let clock = ReplayClock::new(header.started_at);
+    let mut engine = Engine::from_config(config, &header.touchpad, clock)?;
+
+    let mut actions = Vec::new();
+    let mut saved_state = None;
+    // the header is line 1
+    for (line_no, line) in (2..).zip(lines) {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: Record = serde_json::from_str(&line)
+            .with_context(|| format!("Invalid record on line {}", line_no))?;
+        if let Some(time) = record.time() {
+            run_timers_until(&mut engine, time, &mut actions);
+            engine.clock().set(time);
+        }
+        let new_actions = match &record {
+            Record::SavedState(saved) => {
+                saved_state = Some(saved.clone());
+                Vec::new()
+            }
+            Record::InitNumlock(numlock) => {
+                engine.initialize_numlock(*numlock, saved_state.as_ref())
+            }
+            Record::Touchpad(event) => engine.handle_touchpad_event(event),
+            Record::Keyboard { internal, event } => engine.handle_keyboard_event(event, *internal),
+            Record::Typing(time) => engine.handle_typing(*time),
+            // the daemon answered with the error, and carried on
+            Record::Request { request, .. } => engine.handle_request(request).unwrap_or_default(),
+            Record::Config { config, .. } => {
+                let config = parse_config(config)
+                    .with_context(|| format!("Invalid record on line {}", line_no))?;
+                engine.reload(config, &header.touchpad)?
+            }
+        };
+        let now = engine.clock().now();
+        actions.extend(new_actions.into_iter().map(|a| (now, a)));
+    }
+    Ok(actions)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufReader;
+
+    use evdev_rs::enums::{EV_ABS, EV_KEY, EV_MSC, EV_SYN};
+
+    use super::*;
+    use crate::brightness::{Brightness, default_level};
+    use crate::gestures::Resolution;
+    use crate::numpad_layout::BBox;
+
+    fn time(millis: i64) -> TimeVal {
+        TimeVal {
+            tv_sec: 1000 + millis / 1000,
+            tv_usec: (millis % 1000) * 1000,
+        }
+    }
+
+    fn event(millis: i64, code: EventCode, value: i32) -> InputEvent {
+        InputEvent::new(&time(millis), &code, value)
+    }
+
+    /// A tap, given as the events of each frame.
+    fn tap(millis: i64, (x, y): (i32, i32), duration: i64) -> Vec<Record> {
+        let frame = |millis, changes: &[(EV_ABS, i32)]| {
+            changes
+                .iter()
+                .map(move |&(axis, value)| event(millis, EventCode::EV_ABS(axis), value))
+                .chain([
+                    event(millis, EventCode::EV_MSC(EV_MSC::MSC_TIMESTAMP), 0),
+                    event(millis, EventCode::EV_SYN(EV_SYN::SYN_REPORT), 0),
+                ])
+                .map(Record::Touchpad)
+                .collect::<Vec<_>>()
+        };
+        let mut records = frame(
+            millis,
+            &[
+                (EV_ABS::ABS_MT_TRACKING_ID, 1),
+                (EV_ABS::ABS_MT_POSITION_X, x),
+                (EV_ABS::ABS_MT_POSITION_Y, y),
+            ],
+        );
+        records.extend(frame(
+            millis + duration,
+            &[(EV_ABS::ABS_MT_TRACKING_ID, -1)],
+        ));
+        records
+    }
+
+    fn header(config: &str) -> Header {
+        Header {
+            config: config.to_owned(),
+            touchpad: TouchpadInfo {
+                name: "Test touchpad".to_owned(),
+                detected: Some(SupportedLayout::UX433FA),
+                bbox: BBox::new(0, 1000, 0, 1000),
+                resolution: Resolution { x: 10, y: 10 },
+                palm_axes: Default::default(),
+                cur_slot: 0,
+                slots: Vec::new(),
+            },
+            started_at: time(0),
+        }
+    }
+
+    /// Write the records after the header, and replay them.
+    fn record_and_replay(header: &Header, records: &[Record]) -> Vec<(TimeVal, Action)> {
+        let mut recording = serde_json::to_string(header).unwrap();
+        for record in records {
+            recording.push('\n');
+            recording.push_str(&serde_json::to_string(record).unwrap());
+        }
+        replay(recording.as_bytes(), None).unwrap()
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let header = header("idle_timeout = 5");
+        let saved = SavedState {
+            brightness: "half".to_owned(),
+            numlock: false,
+        };
+        let mut records = vec![Record::SavedState(saved), Record::InitNumlock(0)];
+        records.push(Record::Keyboard {
+            internal: false,
+            event: event(100, EventCode::EV_LED(EV_LED::LED_NUML), 1),
+        });
+        records.extend(tap(200, (140, 200), 50));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session.jsonl");
+        let mut recorder = Recorder::create(&path, &header).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        for record in &records {
+            recorder.record(record).unwrap();
+        }
+        drop(recorder);
+        // the idle timer runs out before this
+        let late_tap = tap(10_000, (320, 200), 50);
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        for record in &late_tap {
+            serde_json::to_writer(&mut file, record).unwrap();
+            writeln!(file).unwrap();
+        }
+
+        let actions = replay(BufReader::new(File::open(&path).unwrap()), None).unwrap();
+        assert_eq!(
+            actions,
+            [
+                (time(100), Action::SetBrightness(default_level("half"))),
+                (time(200), Action::Grab),
+                (time(200), Action::KeyDown(vec![EV_KEY::KEY_KP7])),
+                (time(250), Action::KeyUp(vec![EV_KEY::KEY_KP7])),
+                (time(750), Action::Ungrab),
+                (time(5200), Action::SetBrightness(Brightness::off())),
+                (time(5200), Action::ToggleNumlock),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_keyboard_records() {
+        let led = event(100, EventCode::EV_LED(EV_LED::LED_NUML), 1);
+        assert_eq!(
+            Record::keyboard(&led, false),
+            Some(Record::Keyboard {
+                internal: false,
+                event: led
+            })
+        );
+        let key_a = event(200, EventCode::EV_KEY(EV_KEY::KEY_A), 1);
+        assert_eq!(
+            Record::keyboard(&key_a, true),
+            Some(Record::Typing(time(200)))
+        );
+        // external keyboards, releases and the keys that don't count as typing are left out
+        assert_eq!(Record::keyboard(&key_a, false), None);
+        let release = event(300, EventCode::EV_KEY(EV_KEY::KEY_A), 0);
+        assert_eq!(Record::keyboard(&release, true), None);
+        let keypad = event(300, EventCode::EV_KEY(EV_KEY::KEY_KP1), 1);
+        assert_eq!(Record::keyboard(&keypad, true), None);
+    }
+
+    #[test]
+    fn test_replay_requests() {
+        let mut records = vec![
+            Record::InitNumlock(0),
+            Record::Request {
+                time: time(100),
+                request: Request::Enable,
+            },
+            Record::Typing(time(150)),
+            Record::Request {
+                time: time(300),
+                request: Request::Brightness {
+                    level: "low".to_owned(),
+                },
+            },
+            Record::Request {
+                time: time(300),
+                request: Request::Brightness {
+                    level: "blinding".to_owned(),
+                },
+            },
+            Record::Config {
+                time: time(350),
+                config: String::new(),
+            },
+        ];
+        // too soon after typing, if the config hadn't been reloaded
+        records.extend(tap(400, (140, 200), 50));
+        let actions = record_and_replay(&header("disable_while_typing_ms = 300"), &records);
+        assert_eq!(
+            actions,
+            [
+                (time(100), Action::SetBrightness(default_level("full"))),
+                (time(100), Action::ToggleNumlock),
+                (time(300), Action::SetBrightness(default_level("low"))),
+                (time(400), Action::Grab),
+                (time(400), Action::KeyDown(vec![EV_KEY::KEY_KP7])),
+                (time(450), Action::KeyUp(vec![EV_KEY::KEY_KP7])),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_replay_fixture() {
+        let path =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/recordings/kp8.jsonl");
+        let actions = replay(BufReader::new(File::open(path).unwrap()), None).unwrap();
+        let keys: Vec<_> = actions
+            .into_iter()
+            .filter_map(|(_, action)| match action {
+                Action::KeyDown(keys) => Some(keys),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(keys, [vec![EV_KEY::KEY_KP8]]);
+    }
+
+    #[test]
+    fn test_invalid_record() {
+        let header = r#"{"config":"","touchpad":{"name":"","detected":"UX433FA","bbox":{"minx":0,"maxx":1000,"miny":0,"maxy":1000},"resolution":{"x":0,"y":0},"palm_axes":{"touch_major":null,"width_major":null,"pressure":null},"cur_slot":0},"started_at":{"tv_sec":0,"tv_usec":0}}"#;
+        let recording = format!("{}\n{{\"init_numlock\":0}}\n{{\"bogus\":1}}\n", header);
+        let err = replay(recording.as_bytes(), None).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid record on line 3");
+    }
+}
@@ -0,0 +1,101 @@
+use std::io::{self, ErrorKind, Read};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+use anyhow::{Context, Result};
+
+/// Write end of the socket that the signal handler wakes up, `-1` if not installed.
+static WAKE_FD: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn on_hangup(_: libc::c_int) {
+    let fd = WAKE_FD.load(Ordering::Relaxed);
+    if fd < 0 {
+        return;
+    }
+    // Safety: only async-signal-safe functions are used, and errno is restored for the
+    // code that was interrupted
+    unsafe {
+        let errno = *libc::__errno_location();
+        libc::write(fd, [1u8].as_ptr() as *const libc::c_void, 1);
+        *libc::__errno_location() = errno;
+    }
+}
+
+/// Turns SIGHUP into a readable socket, so that it can be polled along with the devices.
+#[derive(Debug)]
+pub(crate) struct Hangup {
+    reader: UnixStream,
+    writer: UnixStream,
+}
+
+impl Hangup {
+    /// Install the handler. There should only be one of these at a time.
+    pub(crate) fn install() -> Result<Self> {
+        let (reader, writer) = UnixStream::pair()?;
+        reader.set_nonblocking(true)?;
+        // a flood of signals must not block the handler
+        writer.set_nonblocking(true)?;
+        WAKE_FD.store(writer.as_raw_fd(), Ordering::Relaxed);
+        // Safety: the handler only does async-signal-safe things
+        let res = unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = on_hangup as *const () as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(libc::SIGHUP, &action, std::ptr::null_mut())
+        };
+        if res != 0 {
+            WAKE_FD.store(-1, Ordering::Relaxed);
+            return Err(io::Error::last_os_error()).context("Unable to handle SIGHUP");
+        }
+        Ok(Self { reader, writer })
+    }
+
+    /// Whether SIGHUP was received since the last call.
+    pub(crate) fn received(&self) -> bool {
+        let mut buf = [0; 64];
+        let mut received = false;
+        loop {
+            match (&self.reader).read(&mut buf) {
+                Ok(0) => return received,
+                Ok(_) => received = true,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => return received,
+            }
+        }
+    }
+}
+
+impl AsRawFd for Hangup {
+    fn as_raw_fd(&self) -> RawFd {
+        self.reader.as_raw_fd()
+    }
+}
+
+impl Drop for Hangup {
+    fn drop(&mut self) {
+        // Safety: restores the default action, which doesn't need the socket
+        unsafe {
+            libc::signal(libc::SIGHUP, libc::SIG_DFL);
+        }
+        let _ = WAKE_FD.compare_exchange(
+            self.writer.as_raw_fd(),
+            -1,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+    }
+}
+
+#[test]
+fn test_hangup() {
+    let hangup = Hangup::install().unwrap();
+    assert!(!hangup.received());
+    unsafe {
+        libc::raise(libc::SIGHUP);
+        libc::raise(libc::SIGHUP);
+    }
+    assert!(hangup.received());
+    assert!(!hangup.received());
+}
@@ -0,0 +1,121 @@
+use std::fs::File;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+/// The parts of the numpad state that are kept across restarts.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct SavedState {
+    /// Name of the brightness level
+    pub(crate) brightness: String,
+    pub(crate) numlock: bool,
+}
+
+/// The file that the state is saved in, under the configured state directory.
+#[derive(Debug)]
+pub(crate) struct StateFile {
+    path: PathBuf,
+    /// What the file has, as far as we know
+    saved: Option<SavedState>,
+}
+
+impl StateFile {
+    const NAME: &str = "state.json";
+
+    /// Read the state left by the last run, if any. A broken file is treated as missing.
+    pub(crate) fn open(dir: &Path) -> Self {
+        let path = dir.join(Self::NAME);
+        let saved = match Self::read(&path) {
+            Ok(saved) => saved,
+            Err(err) => {
+                warn!("{:#}. Starting afresh.", err);
+                None
+            }
+        };
+        debug!("Saved state in {}: {:?}", path.display(), saved);
+        Self { path, saved }
+    }
+
+    fn read(path: &Path) -> Result<Option<SavedState>> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => {
+                return Err(err).with_context(|| format!("Could not read {}", path.display()));
+            }
+        };
+        serde_json::from_str(&text)
+            .map(Some)
+            .with_context(|| format!("Invalid state file {}", path.display()))
+    }
+
+    pub(crate) fn saved(&self) -> Option<&SavedState> {
+        self.saved.as_ref()
+    }
+
+    /// Write the state, if it changed since the last time.
+    ///
+    /// It goes to a temporary file first, which then replaces the old one, so that a
+    /// crash midway doesn't leave a truncated file behind.
+    pub(crate) fn save(&mut self, state: SavedState) -> Result<()> {
+        if self.saved.as_ref() == Some(&state) {
+            return Ok(());
+        }
+        debug!("Saving state {:?}", state);
+        let json = serde_json::to_string(&state)?;
+        // not retried until the state changes again, to not flood the logs on every event
+        self.saved = Some(state);
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Could not create {}", dir.display()))?;
+        }
+        let tmp_path = self.path.with_extension("json.tmp");
+        let write = || -> std::io::Result<()> {
+            let mut tmp = File::create(&tmp_path)?;
+            tmp.write_all(json.as_bytes())?;
+            tmp.sync_all()?;
+            std::fs::rename(&tmp_path, &self.path)
+        };
+        write().map_err(|err| {
+            let _ = std::fs::remove_file(&tmp_path);
+            anyhow::Error::new(err).context(format!("Could not save {}", self.path.display()))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_and_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_dir = dir.path().join("asus-numpad");
+        let mut file = StateFile::open(&state_dir);
+        assert_eq!(file.saved(), None);
+
+        let state = SavedState {
+            brightness: "low".to_owned(),
+            numlock: true,
+        };
+        file.save(state.clone()).unwrap();
+        assert_eq!(StateFile::open(&state_dir).saved(), Some(&state));
+        // only the state file is left behind
+        let names: Vec<_> = std::fs::read_dir(&state_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, [StateFile::NAME]);
+
+        // unchanged, so not written again
+        std::fs::remove_file(state_dir.join(StateFile::NAME)).unwrap();
+        file.save(state).unwrap();
+        assert_eq!(StateFile::open(&state_dir).saved(), None);
+
+        std::fs::write(state_dir.join(StateFile::NAME), "{\"brightness\":").unwrap();
+        assert_eq!(StateFile::open(&state_dir).saved(), None);
+    }
+}
@@ -5,44 +5,8 @@ use anyhow::{Context, Error, Result};
 use i2cdev::core::I2CDevice;
 use i2cdev::linux::{LinuxI2CDevice, LinuxI2CError};
 
-#[derive(Debug, Default, Clone, Copy)]
-pub enum Brightness {
-    Zero = 0,
-    Low = 31,
-    Half = 24,
-    #[default]
-    Full = 1,
-}
-
-impl std::fmt::Display for Brightness {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        use Brightness::*;
-        let level = match self {
-            Zero => "Zero",
-            Low => "Low",
-            Half => "Half",
-            Full => "Full",
-        };
-        f.write_str(level)
-    }
-}
-
-impl Brightness {
-    fn next(&self) -> Self {
-        use Brightness::*;
-        match self {
-            Zero => Default::default(), // Jump to default
-            Low => Half,
-            Half => Full,
-            Full => Low,
-        }
-    }
-
-    pub fn cycle(&mut self) -> Self {
-        *self = self.next();
-        *self
-    }
-}
+use crate::backlight::{Backlight, brightness_report};
+use crate::brightness::Brightness;
 
 pub struct TouchpadI2C {
     dev: LinuxI2CDevice,
@@ -74,23 +38,15 @@ impl TouchpadI2C {
         };
         Ok(Self { dev, i2c_id })
     }
+}
 
-    pub fn set_brightness(&mut self, brightness: Brightness) -> Result<()> {
-        let msg = [
-            0x05,
-            0x00,
-            0x3d,
-            0x03,
-            0x06,
-            0x00,
-            0x07,
-            0x00,
-            0x0d,
-            0x14,
-            0x03,
-            brightness as u8,
-            0xad,
-        ];
+impl Backlight for TouchpadI2C {
+    fn set_brightness(&mut self, brightness: &Brightness) -> Result<()> {
+        // a HID over I2C SET_REPORT command, wrapping the feature report
+        let header = [0x05, 0x00, 0x3d, 0x03, 0x06, 0x00, 0x07, 0x00];
+        let mut msg = [0; 13];
+        msg[..8].copy_from_slice(&header);
+        msg[8..].copy_from_slice(&brightness_report(brightness));
         self.dev
             .write(&msg)
             .with_context(|| format!("Could not set touchpad brightness to {}", brightness))
@@ -14,9 +14,32 @@ pub(crate) struct CustomDuration {
 }
 
 impl CustomDuration {
+    /// Saturates instead of overflowing, so that any config value can be converted.
     pub(crate) const fn from_millis(millis: u64) -> Self {
         Self {
-            micros: millis * 1000,
+            micros: millis.saturating_mul(1000),
+        }
+    }
+
+    pub(crate) const fn from_secs(secs: u64) -> Self {
+        Self::from_millis(secs.saturating_mul(1000))
+    }
+
+    /// The duration in milliseconds, rounded up.
+    pub(crate) const fn as_millis_ceil(&self) -> u64 {
+        self.micros.div_ceil(1000)
+    }
+
+    /// This duration scaled by `num / den`, rounded down.
+    pub(crate) const fn mul_div(self, num: u64, den: u64) -> Self {
+        Self {
+            micros: (self.micros as u128 * num as u128 / den as u128) as u64,
+        }
+    }
+
+    pub(crate) const fn saturating_sub(self, other: Self) -> Self {
+        Self {
+            micros: self.micros.saturating_sub(other.micros),
         }
     }
 }
@@ -52,6 +75,72 @@ impl ElapsedSince for TimeVal {
     }
 }
 
+/// The time `duration` after `time`.
+pub(crate) fn add_duration(time: TimeVal, duration: CustomDuration) -> TimeVal {
+    const MICROS_PER_SEC: u64 = 1_000_000;
+    let micros = time.tv_usec as u64 + duration.micros;
+    TimeVal {
+        tv_sec: time.tv_sec + (micros / MICROS_PER_SEC) as i64,
+        tv_usec: (micros % MICROS_PER_SEC) as i64,
+    }
+}
+
+/// Source of the current time.
+///
+/// Must use the same clock as the evdev timestamps, so that the two can be compared.
+pub(crate) trait Clock {
+    fn now(&self) -> TimeVal;
+}
+
+/// Reads `CLOCK_MONOTONIC`, which the evdev devices are set to use as well.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct MonotonicClock;
+
+impl Clock for MonotonicClock {
+    fn now(&self) -> TimeVal {
+        let mut ts = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        // Safety: ts is a valid timespec, and CLOCK_MONOTONIC is always supported
+        unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+        TimeVal {
+            tv_sec: ts.tv_sec,
+            tv_usec: ts.tv_nsec / 1000,
+        }
+    }
+}
+
+/// A clock that only moves when told to.
+#[cfg(test)]
+#[derive(Debug)]
+pub(crate) struct FakeClock {
+    now: std::cell::Cell<TimeVal>,
+}
+
+#[cfg(test)]
+impl FakeClock {
+    pub(crate) fn new() -> Self {
+        Self {
+            now: std::cell::Cell::new(TimeVal {
+                tv_sec: 1000,
+                tv_usec: 0,
+            }),
+        }
+    }
+
+    pub(crate) fn advance(&self, duration: CustomDuration) {
+        self.now.set(add_duration(self.now.get(), duration));
+    }
+}
+
+#[cfg(test)]
+impl Clock for FakeClock {
+    fn now(&self) -> TimeVal {
+        self.now.get()
+    }
+}
+
 #[test]
 fn test_elapsed_since() {
     let t1 = TimeVal {
@@ -64,3 +153,10 @@ fn test_elapsed_since() {
     };
     assert_eq!(t2.elapsed_since(t1), CustomDuration { micros: 999_900 });
 }
+
+#[test]
+fn test_duration_saturates() {
+    assert_eq!(CustomDuration::from_secs(u64::MAX).micros, u64::MAX);
+    let duration = CustomDuration::from_millis(u64::MAX / 10);
+    assert_eq!(duration.mul_div(1, 2).micros, u64::MAX / 2);
+}